@@ -1,94 +1,283 @@
-use lazy_static::lazy_static;
-use serde_json::{Map, Value};
-use std::path::{self, Path, PathBuf};
-use std::{env, error::Error};
-use tokio::net::UdpSocket;
-
-use clap::{command, Arg, Command};
-
-lazy_static! {
-    // Reference to application's directory
-    pub static ref APP_DIR: path::PathBuf = {
-        let home = env::var("HOME").expect("HOME environment variable not found");
-        Path::new(&home).join(".resk")
-    };
-    pub static ref BACKEND_ADDR: String = {
-        let addr = format!("127.0.0.1:{}", get_port().expect("Check if resk_node is running"));
-        addr
-    };
-}
+use resk_client::{
+    Direction, Event, PairingState, PairingSummary, PeerPolicy, ProtoError,
+    ReskClient,
+};
+use resk_proto::transport::app_dir;
+use std::error::Error;
+use std::io::{self, Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::{sleep, Duration};
+
+use qrcode::{render::unicode, QrCode};
+
+use clap::{command, value_parser, Arg, ArgAction, ArgMatches, Command};
 
 pub async fn run() -> Result<(), Box<dyn Error>> {
-    // First check
-    let alive_ping = send_msg("is_alive:".to_string());
     let matches = command!()
         .subcommand(
             Command::new("get_peers").about("Get peers in local network"),
         )
+        .subcommand(Command::new("peers").about("List trusted peers"))
         .subcommand(
             Command::new("add_peer")
-                .about("Add peer to resk network")
+                .about("Pair with peer and add it to resk network")
+                .arg(Arg::new("peer_id").required(true)),
+        )
+        .subcommand(
+            Command::new("alias")
+                .about("Set local name of peer, clears it if no alias given")
+                .arg(Arg::new("peer_id").required(true))
+                .arg(Arg::new("alias")),
+        )
+        .subcommand(
+            Command::new("remove_peer")
+                .about("Remove peer from resk network")
                 .arg(Arg::new("peer_id").required(true)),
         )
+        .subcommand(
+            Command::new("pair")
+                .about("Answer pairing requests from peers")
+                .subcommand(
+                    Command::new("confirm")
+                        .about("Confirm pairing code shown for peer")
+                        .arg(Arg::new("peer_id").required(true)),
+                )
+                .subcommand(
+                    Command::new("reject")
+                        .about("Reject pairing with peer")
+                        .arg(Arg::new("peer_id").required(true)),
+                )
+                .subcommand(
+                    Command::new("uri")
+                        .about("Show pairing uri and its QR code"),
+                )
+                .subcommand(
+                    Command::new("redeem")
+                        .about("Pair with node that generated pairing uri")
+                        .arg(Arg::new("uri").required(true)),
+                ),
+        )
+        .subcommand(
+            Command::new("swarm_key")
+                .about("Show key of private network this node is in")
+                .subcommand(
+                    Command::new("generate")
+                        .about("Generate key for a new private network"),
+                ),
+        )
+        .subcommand(
+            Command::new("connect")
+                .about("Connect to peer outside of local network")
+                .arg(Arg::new("multiaddr").required(true)),
+        )
         .subcommand(Command::new("local").about("Get local peer id"))
+        .subcommand(
+            Command::new("policy")
+                .about("Show or set what is shared with peers")
+                .arg(Arg::new("peer_id"))
+                .arg(
+                    Arg::new("policy")
+                        .value_parser(["send", "receive", "both", "none"])
+                        .requires("peer_id"),
+                ),
+        )
+        .subcommand(
+            Command::new("clip")
+                .about("Control shared clipboard")
+                .subcommand(
+                    Command::new("send")
+                        .about("Share text, use - to read it from stdin")
+                        .arg(Arg::new("text").required(true)),
+                )
+                .subcommand(
+                    Command::new("get").about("Print local clipboard content"),
+                )
+                .subcommand(
+                    Command::new("history")
+                        .about("List clipboard history")
+                        .subcommand(
+                            Command::new("search")
+                                .about("Search clipboard history")
+                                .arg(Arg::new("query").required(true)),
+                        )
+                        .subcommand(
+                            Command::new("show")
+                                .about("Show history entry")
+                                .arg(
+                                    Arg::new("id")
+                                        .required(true)
+                                        .value_parser(value_parser!(u64)),
+                                ),
+                        )
+                        .subcommand(
+                            Command::new("apply")
+                                .about("Put history entry on local clipboard")
+                                .arg(
+                                    Arg::new("id")
+                                        .required(true)
+                                        .value_parser(value_parser!(u64)),
+                                ),
+                        ),
+                )
+                .subcommand(
+                    Command::new("pause")
+                        .about("Pause clipboard sharing")
+                        .args(direction_args()),
+                )
+                .subcommand(
+                    Command::new("resume")
+                        .about("Resume clipboard sharing")
+                        .args(direction_args()),
+                )
+                .subcommand(
+                    Command::new("status")
+                        .about("Show clipboard sharing state"),
+                )
+                .subcommand_required(true),
+        )
+        .subcommand(
+            Command::new("watch")
+                .about("Print events of node as they happen")
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .action(ArgAction::SetTrue)
+                        .help("Print events as json lines"),
+                ),
+        )
         .subcommand_required(true)
         .get_matches();
-    alive_ping.await?;
+    // First check
+    let mut client = ReskClient::connect().await?;
+    client.is_alive().await?;
     if let Some(_matches) = matches.subcommand_matches("get_peers") {
         println!("block1");
-        get_peers().await?;
+        get_peers(&mut client).await?;
+    }
+    if matches.subcommand_matches("peers").is_some() {
+        get_known_peers(&mut client).await?;
     }
     if let Some(matches) = matches.subcommand_matches("add_peer") {
         let peer_id = matches.get_one::<String>("peer_id").unwrap();
-        add_peer(peer_id).await?;
+        add_peer(&mut client, peer_id).await?;
+    }
+    if let Some(matches) = matches.subcommand_matches("alias") {
+        let peer_id = matches.get_one::<String>("peer_id").unwrap();
+        let alias = matches.get_one::<String>("alias");
+        set_alias(&mut client, peer_id, alias).await?;
+    }
+    if let Some(matches) = matches.subcommand_matches("remove_peer") {
+        let peer_id = matches.get_one::<String>("peer_id").unwrap();
+        remove_peer(&mut client, peer_id).await?;
+    }
+    if let Some(matches) = matches.subcommand_matches("pair") {
+        match matches.subcommand() {
+            Some(("confirm", matches)) => {
+                let peer_id = matches.get_one::<String>("peer_id").unwrap();
+                answer_pairing(&mut client, peer_id, true).await?;
+            }
+            Some(("reject", matches)) => {
+                let peer_id = matches.get_one::<String>("peer_id").unwrap();
+                answer_pairing(&mut client, peer_id, false).await?;
+            }
+            Some(("uri", _)) => get_pairing_uri(&mut client).await?,
+            Some(("redeem", matches)) => {
+                let uri = matches.get_one::<String>("uri").unwrap();
+                redeem_pairing_uri(&mut client, uri).await?;
+            }
+            _ => answer_pairings(&mut client).await?,
+        }
+    }
+    if let Some(matches) = matches.subcommand_matches("swarm_key") {
+        match matches.subcommand() {
+            Some(("generate", _)) => generate_swarm_key(&mut client).await?,
+            _ => get_swarm_key(&mut client).await?,
+        }
+    }
+    if let Some(matches) = matches.subcommand_matches("connect") {
+        let addr = matches.get_one::<String>("multiaddr").unwrap();
+        connect(&mut client, addr).await?;
     }
     if let Some(_matches) = matches.subcommand_matches("local") {
-        get_local_peer_id().await?
+        get_local_peer_id(&mut client).await?
+    }
+    if let Some(matches) = matches.subcommand_matches("policy") {
+        let peer_id = matches.get_one::<String>("peer_id");
+        match matches.get_one::<String>("policy") {
+            Some(policy) => {
+                let policy = get_policy(policy);
+                set_policy(&mut client, peer_id.unwrap(), policy).await?
+            }
+            None => get_policies(&mut client, peer_id).await?,
+        }
+    }
+    if let Some(matches) = matches.subcommand_matches("clip") {
+        match matches.subcommand() {
+            Some(("send", matches)) => {
+                let text = matches.get_one::<String>("text").unwrap();
+                send_clip(&mut client, text).await?;
+            }
+            Some(("get", _)) => get_clip(&mut client).await?,
+            Some(("history", matches)) => match matches.subcommand() {
+                Some(("search", matches)) => {
+                    let query = matches.get_one::<String>("query").unwrap();
+                    get_history(&mut client, Some(query)).await?;
+                }
+                Some(("show", matches)) => {
+                    let id = matches.get_one::<u64>("id").unwrap();
+                    show_history_entry(&mut client, *id).await?;
+                }
+                Some(("apply", matches)) => {
+                    let id = matches.get_one::<u64>("id").unwrap();
+                    apply_history_entry(&mut client, *id).await?;
+                }
+                _ => get_history(&mut client, None).await?,
+            },
+            Some(("pause", matches)) => {
+                set_sync_paused(&mut client, true, get_direction(matches))
+                    .await?
+            }
+            Some(("resume", matches)) => {
+                set_sync_paused(&mut client, false, get_direction(matches))
+                    .await?
+            }
+            Some(("status", _)) => get_sync_status(&mut client).await?,
+            _ => {}
+        }
+    }
+    // Connection is taken over by events
+    if let Some(matches) = matches.subcommand_matches("watch") {
+        return watch(client, matches.get_flag("json")).await;
     }
     Ok(())
 }
 
-fn get_port() -> Result<String, Box<dyn Error>> {
-    let data_path = APP_DIR.clone().join("data.json");
-    let data_map = load_data_map(&data_path);
-    let data_map = match data_map {
-        Ok(data_map) => data_map,
-        Err(_) => return Err("Check if resk_node is running".into()),
-    };
-    let port = data_map.get("port");
-    let port = match port {
-        Some(port) => port.as_str().to_owned().unwrap().to_string(),
-        None => return Err("Check if resk_node is running".into()),
-    };
-    Ok(port)
-}
-
-async fn send_msg(request: String) -> Result<String, Box<dyn Error>> {
-    let mut buf: Vec<u8> = Vec::new();
-    let sender = UdpSocket::bind("127.0.0.1:0").await?;
-    sender
-        .send_to(request.as_bytes(), BACKEND_ADDR.clone())
-        .await
-        .expect("Failed to send");
-    sender
-        .recv_buf(&mut buf)
-        .await
-        .expect("Failed to recv back");
-    Ok(String::from_utf8_lossy(&buf).to_string())
+// Errors reported by node are shown to user, others end the command
+fn report(err: Box<dyn Error>) -> Result<(), Box<dyn Error>> {
+    match err.downcast::<ProtoError>() {
+        Ok(err) => {
+            println!("Something happend: {err}");
+            Ok(())
+        }
+        Err(err) => Err(err),
+    }
 }
 
-async fn get_peers() -> Result<(), Box<dyn Error>> {
-    let peers = send_msg("get_peers:".to_string()).await?;
+async fn get_peers(client: &mut ReskClient) -> Result<(), Box<dyn Error>> {
+    let peers = match client.online_peers().await {
+        Ok(peers) => peers,
+        Err(err) => return report(err),
+    };
 
     if !peers.is_empty() {
         println!("Peers in local network:");
-        for peer_record in peers.split(",").into_iter() {
-            if !peer_record.is_empty() {
-                let peer_id =
-                    peer_record.split(":").nth(0).expect("Incorrect response");
-                let address =
-                    peer_record.split(":").nth(1).expect("Incorrect response");
-                println!("{} with address {}", peer_id, address)
+        for peer in peers {
+            match peer.name {
+                Some(name) => println!(
+                    "{} ({}) with address {}",
+                    name, peer.peer_id, peer.addr
+                ),
+                None => println!("{} with address {}", peer.peer_id, peer.addr),
             }
         }
     } else {
@@ -97,29 +286,497 @@ async fn get_peers() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-async fn add_peer(peer_id: &String) -> Result<(), Box<dyn Error>> {
-    let response = send_msg(format!("add_peer:{}", peer_id)).await?;
-    if response != "OK".to_string() {
-        println!("Something happend: {}", response);
+async fn get_known_peers(
+    client: &mut ReskClient,
+) -> Result<(), Box<dyn Error>> {
+    let records = client.peers().await?;
+    if records.is_empty() {
+        println!("No known peers");
+    }
+    for record in records {
+        let last_seen = record
+            .last_seen
+            .map(|timestamp| format!("seen {}", format_age(timestamp)))
+            .unwrap_or_else(|| "never seen".to_string());
+        match record.display_name() {
+            Some(name) => print!("{name} ({})", record.peer_id),
+            None => print!("{}", record.peer_id),
+        }
+        println!("  {}  {last_seen}", record.policy.as_str());
+        if let Some(addr) = record.addrs.first() {
+            println!("    {addr}");
+        }
+    }
+    Ok(())
+}
+
+async fn add_peer(
+    client: &mut ReskClient,
+    peer_id: &str,
+) -> Result<(), Box<dyn Error>> {
+    if let Err(err) = client.add_peer(peer_id).await {
+        return report(err);
+    }
+    println!("Waiting for {peer_id} to answer...");
+    let pairing = wait_for_pairing(client, peer_id, |pairing| {
+        pairing.state != PairingState::Waiting
+    })
+    .await?;
+    confirm_pairing(client, &pairing).await
+}
+
+async fn set_alias(
+    client: &mut ReskClient,
+    peer_id: &str,
+    alias: Option<&String>,
+) -> Result<(), Box<dyn Error>> {
+    let alias = alias
+        .map(|alias| alias.trim())
+        .filter(|alias| !alias.is_empty());
+    match (client.set_alias(peer_id, alias).await, alias) {
+        (Err(err), _) => return report(err),
+        (Ok(()), Some(alias)) => println!("{peer_id} is now known as {alias}"),
+        (Ok(()), None) => println!("Alias has been removed"),
+    }
+    Ok(())
+}
+
+async fn remove_peer(
+    client: &mut ReskClient,
+    peer_id: &str,
+) -> Result<(), Box<dyn Error>> {
+    match client.remove_peer(peer_id).await {
+        Ok(()) => println!("Peer has been removed successfully"),
+        Err(err) => return report(err),
+    }
+    Ok(())
+}
+
+async fn connect(
+    client: &mut ReskClient,
+    addr: &str,
+) -> Result<(), Box<dyn Error>> {
+    match client.dial(addr).await {
+        Ok(()) => {
+            println!("Dialing {addr}, check get_peers once it is connected")
+        }
+        Err(err) => return report(err),
+    }
+    Ok(())
+}
+
+// Poll node until pairing with peer reaches expected state
+// Peer can be given by its peer id or name
+async fn wait_for_pairing(
+    client: &mut ReskClient,
+    peer_id: &str,
+    done: fn(&PairingSummary) -> bool,
+) -> Result<PairingSummary, Box<dyn Error>> {
+    loop {
+        let pairing = client
+            .pairings()
+            .await?
+            .into_iter()
+            .find(|pairing| {
+                pairing.peer_id == peer_id
+                    || pairing.name.as_deref() == Some(peer_id)
+            })
+            .ok_or("Pairing was not found")?;
+        if done(&pairing) {
+            return Ok(pairing);
+        }
+        sleep(Duration::from_millis(500)).await;
+    }
+}
+
+// Ask user to compare code with the one shown on the other device
+async fn confirm_pairing(
+    client: &mut ReskClient,
+    pairing: &PairingSummary,
+) -> Result<(), Box<dyn Error>> {
+    if let PairingState::Failed(reason) = &pairing.state {
+        println!("Pairing failed: {reason}");
+        return Ok(());
+    }
+    let code = pairing.code.clone().unwrap_or_default();
+    match &pairing.name {
+        Some(name) => println!("Pairing with {} ({})", name, pairing.peer_id),
+        None => println!("Pairing with {}", pairing.peer_id),
+    }
+    println!("Code: {code}");
+    print!("Does the other device show the same code? [y/N] ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    let confirmed = answer.trim().eq_ignore_ascii_case("y");
+    answer_pairing(client, &pairing.peer_id, confirmed).await
+}
+
+async fn answer_pairing(
+    client: &mut ReskClient,
+    peer_id: &str,
+    confirmed: bool,
+) -> Result<(), Box<dyn Error>> {
+    let answered = if confirmed {
+        client.pair_confirm(peer_id).await
     } else {
-        println!("Peer has been added successfully");
+        client.pair_reject(peer_id).await
+    };
+    if let Err(err) = answered {
+        return report(err);
+    }
+    if !confirmed {
+        println!("Pairing has been rejected");
+        return Ok(());
+    }
+    println!("Waiting for confirmation on the other device...");
+    let pairing = wait_for_pairing(client, peer_id, |pairing| {
+        pairing.state != PairingState::Confirming
+    })
+    .await?;
+    print_pairing_result(pairing.state);
+    Ok(())
+}
+
+fn print_pairing_result(state: PairingState) {
+    match state {
+        PairingState::Paired => println!("Peer has been added successfully"),
+        PairingState::Failed(reason) => println!("Pairing failed: {reason}"),
+        _ => println!("Pairing failed"),
+    }
+}
+
+async fn get_pairing_uri(
+    client: &mut ReskClient,
+) -> Result<(), Box<dyn Error>> {
+    let uri = match client.pair_uri().await {
+        Ok(uri) => uri,
+        Err(err) => return report(err),
+    };
+    let qr = QrCode::new(uri.as_bytes())?;
+    println!(
+        "{}",
+        qr.render::<unicode::Dense1x2>().quiet_zone(true).build()
+    );
+    println!("{uri}");
+    println!("Scan it or run `resk pair redeem <uri>` on the other device");
+    Ok(())
+}
+
+async fn redeem_pairing_uri(
+    client: &mut ReskClient,
+    uri: &str,
+) -> Result<(), Box<dyn Error>> {
+    if let Err(err) = client.pair_redeem(uri).await {
+        return report(err);
+    }
+    let peer_id = uri
+        .split(['?', '&'])
+        .find_map(|param| param.strip_prefix("peer="))
+        .ok_or("Pairing uri has no peer")?;
+    println!("Pairing with {peer_id}...");
+    let pairing = wait_for_pairing(client, peer_id, |pairing| {
+        matches!(
+            pairing.state,
+            PairingState::Paired | PairingState::Failed(_)
+        )
+    })
+    .await?;
+    print_pairing_result(pairing.state);
+    Ok(())
+}
+
+// Confirm or reject every pairing waiting for local user
+async fn answer_pairings(
+    client: &mut ReskClient,
+) -> Result<(), Box<dyn Error>> {
+    let pending: Vec<PairingSummary> = client
+        .pairings()
+        .await?
+        .into_iter()
+        .filter(|pairing| {
+            pairing.state == PairingState::Confirming
+                && !pairing.local_confirmed
+        })
+        .collect();
+    if pending.is_empty() {
+        println!("No pending pairing requests");
+    }
+    for pairing in pending.iter() {
+        confirm_pairing(client, pairing).await?;
+    }
+    Ok(())
+}
+
+async fn get_swarm_key(client: &mut ReskClient) -> Result<(), Box<dyn Error>> {
+    match client.swarm_key().await? {
+        Some(fingerprint) => {
+            println!("Private network with key fingerprint {fingerprint}")
+        }
+        None => println!("Node is not in a private network"),
+    }
+    Ok(())
+}
+
+async fn generate_swarm_key(
+    client: &mut ReskClient,
+) -> Result<(), Box<dyn Error>> {
+    let fingerprint = match client.generate_swarm_key().await {
+        Ok(fingerprint) => fingerprint,
+        Err(err) => return report(err),
+    };
+    println!("Swarm key with fingerprint {fingerprint} has been generated");
+    println!(
+        "Copy {} to every device of the network and restart resk_node",
+        app_dir()?.join("swarm.key").display()
+    );
+    Ok(())
+}
+
+async fn get_local_peer_id(
+    client: &mut ReskClient,
+) -> Result<(), Box<dyn Error>> {
+    let peer_id = client.local_peer_id().await?;
+    println!("Local peer id is -> {peer_id}");
+    Ok(())
+}
+
+async fn get_policies(
+    client: &mut ReskClient,
+    peer_id: Option<&String>,
+) -> Result<(), Box<dyn Error>> {
+    let records = client.peers().await?;
+    if records.is_empty() {
+        println!("No known peers");
+    }
+    for record in records.iter() {
+        let peer = &record.peer_id;
+        let name = record.display_name();
+        if peer_id.is_some_and(|id| id != peer && Some(id.as_str()) != name) {
+            continue;
+        }
+        match name {
+            Some(name) => {
+                println!("{name} ({peer})  {}", record.policy.as_str())
+            }
+            None => println!("{peer}  {}", record.policy.as_str()),
+        }
     }
     Ok(())
 }
 
-fn load_data_map(path: &PathBuf) -> Result<Map<String, Value>, Box<dyn Error>> {
-    let data: Map<String, Value>;
-    if !path.exists() {
-        data = Map::new();
+async fn set_policy(
+    client: &mut ReskClient,
+    peer_id: &str,
+    policy: PeerPolicy,
+) -> Result<(), Box<dyn Error>> {
+    match client.set_policy(peer_id, policy).await {
+        Ok(()) => {
+            println!("Policy of {peer_id} has been set to {}", policy.as_str())
+        }
+        Err(err) => return report(err),
+    }
+    Ok(())
+}
+
+async fn send_clip(
+    client: &mut ReskClient,
+    text: &str,
+) -> Result<(), Box<dyn Error>> {
+    let mut text = text.to_string();
+    if text == "-" {
+        text.clear();
+        io::stdin().read_to_string(&mut text)?;
+    }
+    client.clip_send(&text).await.or_else(report)
+}
+
+async fn get_clip(client: &mut ReskClient) -> Result<(), Box<dyn Error>> {
+    match client.clip_get().await {
+        Ok(text) => print!("{text}"),
+        Err(err) => return report(err),
+    }
+    Ok(())
+}
+
+// Limit pause and resume to a single direction
+fn direction_args() -> [Arg; 2] {
+    [
+        Arg::new("incoming")
+            .long("incoming")
+            .action(ArgAction::SetTrue)
+            .conflicts_with("outgoing")
+            .help("Only updates from peers"),
+        Arg::new("outgoing")
+            .long("outgoing")
+            .action(ArgAction::SetTrue)
+            .help("Only local copies"),
+    ]
+}
+
+fn get_direction(matches: &ArgMatches) -> Direction {
+    if matches.get_flag("incoming") {
+        Direction::Incoming
+    } else if matches.get_flag("outgoing") {
+        Direction::Outgoing
+    } else {
+        Direction::Both
+    }
+}
+
+// Values are limited by clap to the ones below
+fn get_policy(policy: &str) -> PeerPolicy {
+    match policy {
+        "send" => PeerPolicy::Send,
+        "receive" => PeerPolicy::Receive,
+        "none" => PeerPolicy::None,
+        _ => PeerPolicy::Both,
+    }
+}
+
+async fn set_sync_paused(
+    client: &mut ReskClient,
+    paused: bool,
+    direction: Direction,
+) -> Result<(), Box<dyn Error>> {
+    let result = if paused {
+        client.pause(direction).await
     } else {
-        let json_str = std::fs::read_to_string(&path)?;
-        data = serde_json::from_str(&json_str)?;
+        client.resume(direction).await
+    };
+    let direction = direction.as_str();
+    match result {
+        Err(err) => return report(err),
+        Ok(()) if paused => {
+            println!("Clipboard sharing has been paused ({direction})")
+        }
+        Ok(()) => println!("Clipboard sharing has been resumed ({direction})"),
     }
-    Ok(data)
+    Ok(())
 }
 
-async fn get_local_peer_id() -> Result<(), Box<dyn Error>> {
-    let response = send_msg("local_peer_id:".to_string()).await?;
-    println!("Local peer id is -> {response}");
+async fn get_sync_status(
+    client: &mut ReskClient,
+) -> Result<(), Box<dyn Error>> {
+    let status = client.sync_status().await?;
+    let state = |paused: bool| if paused { "paused" } else { "active" };
+    println!("Incoming: {}", state(status.incoming));
+    println!("Outgoing: {}", state(status.outgoing));
     Ok(())
 }
+
+// Whole history, or entries matching query
+async fn get_history(
+    client: &mut ReskClient,
+    query: Option<&String>,
+) -> Result<(), Box<dyn Error>> {
+    let entries = client.history(query.map(String::as_str)).await?;
+    if entries.is_empty() {
+        println!("Clipboard history is empty");
+    }
+    for entry in entries {
+        println!(
+            "{:>4}  {:>8}  {}  {}  {}",
+            entry.id,
+            format_age(entry.timestamp),
+            entry.origin_name.unwrap_or(entry.origin),
+            entry.mime,
+            entry.preview
+        );
+    }
+    Ok(())
+}
+
+async fn show_history_entry(
+    client: &mut ReskClient,
+    id: u64,
+) -> Result<(), Box<dyn Error>> {
+    match client.history_show(id).await {
+        Ok(text) => println!("{text}"),
+        Err(err) => return report(err),
+    }
+    Ok(())
+}
+
+async fn apply_history_entry(
+    client: &mut ReskClient,
+    id: u64,
+) -> Result<(), Box<dyn Error>> {
+    match client.history_apply(id).await {
+        Ok(()) => println!("History entry has been put on clipboard"),
+        Err(err) => return report(err),
+    }
+    Ok(())
+}
+
+// Runs until node stops or user interrupts it
+async fn watch(client: ReskClient, json: bool) -> Result<(), Box<dyn Error>> {
+    let mut events = client.subscribe().await?;
+    while let Some(event) = events.next().await? {
+        if json {
+            println!("{}", serde_json::to_string(&event)?);
+        } else {
+            println!("{}", describe_event(event));
+        }
+    }
+    println!("Node has stopped");
+    Ok(())
+}
+
+fn describe_event(event: Event) -> String {
+    let peer = |peer_id: String, name: Option<String>| match name {
+        Some(name) => format!("{name} ({peer_id})"),
+        None => peer_id,
+    };
+    match event {
+        Event::PeerDiscovered {
+            peer_id,
+            name,
+            addr,
+        } => format!("Discovered {} on {addr}", peer(peer_id, name)),
+        Event::PeerExpired {
+            peer_id,
+            name,
+            addr,
+        } => format!("Lost {} on {addr}", peer(peer_id, name)),
+        Event::ConnectionEstablished {
+            peer_id,
+            name,
+            addr,
+        } => format!("Connected to {} on {addr}", peer(peer_id, name)),
+        Event::ConnectionClosed { peer_id, name } => {
+            format!("Disconnected from {}", peer(peer_id, name))
+        }
+        Event::ClipboardReceived {
+            origin,
+            origin_name,
+            mime,
+            size,
+        } => format!(
+            "Received {mime}, {size} bytes from {}",
+            peer(origin, origin_name)
+        ),
+        Event::ClipboardSent { mime, size, peers } => {
+            format!("Shared {mime}, {size} bytes with {} peers", peers.len())
+        }
+        Event::PairingRequested { peer_id, name } => format!(
+            "{} wants to pair, run `resk pair` to answer",
+            peer(peer_id, name)
+        ),
+    }
+}
+
+// Time passed since unix timestamp in milliseconds, e.g. 3h ago
+fn format_age(timestamp: u64) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default();
+    let seconds = now.saturating_sub(timestamp) / 1000;
+    match seconds {
+        0..=59 => format!("{seconds}s ago"),
+        60..=3599 => format!("{}m ago", seconds / 60),
+        3600..=86399 => format!("{}h ago", seconds / 3600),
+        _ => format!("{}d ago", seconds / 86400),
+    }
+}
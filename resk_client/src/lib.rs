@@ -0,0 +1,424 @@
+// Async client of a running resk node
+// Desktop nodes are reached over their control socket, other platforms over
+// udp on the port node saved to its data.json
+// Errors reported by node are returned as boxed ProtoError, so callers can
+// downcast them to tell them apart from connection errors
+use resk_proto::transport::{app_dir, MAX_DATAGRAM_SIZE};
+use resk_proto::{RequestFrame, ResponseFrame};
+use serde_json::{Map, Value};
+use std::error::Error;
+use std::fs;
+use tokio::net::UdpSocket;
+use tokio::time::{timeout, Duration};
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use {
+    resk_proto::transport::{read_frame, socket_path, write_frame},
+    std::path::Path,
+    tokio::net::UnixStream,
+};
+
+pub use resk_proto::{
+    Direction, ErrorCode, Event, HistorySummary, OnlinePeer, PairingState,
+    PairingSummary, PeerInfo, PeerPolicy, ProtoError, Request, Response,
+    SyncStatus,
+};
+
+// Node answers right away, interactive commands poll it
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const NOT_RUNNING: &str = "Check if resk_node is running";
+const NO_EVENTS: &str = "Events can only be streamed over control socket";
+const CLOSED: &str = "Connection to node was closed after a timeout";
+
+enum Connection {
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    Socket(UnixStream),
+    // Connected to node, so only its datagrams are received
+    Udp(UdpSocket),
+    // Stream dropped after a request timed out, it may be left mid-frame
+    Closed,
+}
+
+pub struct ReskClient {
+    connection: Connection,
+    // Id of next request, node echoes it in its response
+    next_id: u64,
+}
+
+impl ReskClient {
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub async fn connect() -> Result<Self, Box<dyn Error>> {
+        Self::connect_socket(&socket_path()?).await
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    pub async fn connect() -> Result<Self, Box<dyn Error>> {
+        Self::connect_udp(udp_port()?).await
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub async fn connect_socket(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let stream =
+            UnixStream::connect(path).await.map_err(|_| NOT_RUNNING)?;
+        Ok(Self::new(Connection::Socket(stream)))
+    }
+
+    // Node listening for udp requests on localhost
+    pub async fn connect_udp(port: u16) -> Result<Self, Box<dyn Error>> {
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        socket.connect(("127.0.0.1", port)).await?;
+        Ok(Self::new(Connection::Udp(socket)))
+    }
+
+    fn new(connection: Connection) -> Self {
+        ReskClient {
+            connection,
+            next_id: 1,
+        }
+    }
+
+    pub async fn request(
+        &mut self,
+        request: Request,
+    ) -> Result<Response, Box<dyn Error>> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let request = serde_json::to_vec(&RequestFrame::new(id, request))?;
+        match timeout(REQUEST_TIMEOUT, self.exchange(id, &request)).await {
+            Ok(response) => Ok(response?.result?),
+            Err(_) => {
+                if !matches!(self.connection, Connection::Udp(_)) {
+                    self.connection = Connection::Closed;
+                }
+                Err("Node did not respond in time".into())
+            }
+        }
+    }
+
+    async fn exchange(
+        &mut self,
+        id: u64,
+        request: &[u8],
+    ) -> Result<ResponseFrame, Box<dyn Error>> {
+        match &mut self.connection {
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            Connection::Socket(stream) => {
+                write_frame(stream, request).await?;
+                loop {
+                    let frame = read_frame(stream)
+                        .await?
+                        .ok_or("Node closed connection")?;
+                    let response: ResponseFrame =
+                        serde_json::from_slice(&frame)?;
+                    // Late answers to earlier requests are skipped
+                    if response.id < id {
+                        continue;
+                    }
+                    if response.id != id {
+                        return Err(format!(
+                            "Node answered request {} instead of {id}",
+                            response.id
+                        )
+                        .into());
+                    }
+                    return Ok(response);
+                }
+            }
+            Connection::Udp(socket) => {
+                if request.len() > MAX_DATAGRAM_SIZE {
+                    return Err("Request is too big to be sent".into());
+                }
+                socket.send(request).await?;
+                let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+                loop {
+                    let len = socket.recv(&mut buf).await?;
+                    let response: ResponseFrame =
+                        serde_json::from_slice(&buf[..len])?;
+                    // Late answers to requests that timed out are skipped
+                    if response.id == id {
+                        return Ok(response);
+                    }
+                }
+            }
+            Connection::Closed => Err(CLOSED.into()),
+        }
+    }
+
+    // Request answered with plain Ok
+    async fn expect_ok(
+        &mut self,
+        request: Request,
+    ) -> Result<(), Box<dyn Error>> {
+        match self.request(request).await? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub async fn is_alive(&mut self) -> Result<(), Box<dyn Error>> {
+        self.expect_ok(Request::IsAlive).await
+    }
+
+    pub async fn local_peer_id(&mut self) -> Result<String, Box<dyn Error>> {
+        match self.request(Request::LocalPeerId).await? {
+            Response::PeerId(peer_id) => Ok(peer_id),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    // Peers found on local network or connected directly
+    pub async fn online_peers(
+        &mut self,
+    ) -> Result<Vec<OnlinePeer>, Box<dyn Error>> {
+        match self.request(Request::GetPeers).await? {
+            Response::OnlinePeers(peers) => Ok(peers),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    // Trusted peers
+    pub async fn peers(&mut self) -> Result<Vec<PeerInfo>, Box<dyn Error>> {
+        match self.request(Request::Peers).await? {
+            Response::Peers(peers) => Ok(peers),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    // Start pairing, peer is trusted once both users confirmed the code
+    pub async fn add_peer(&mut self, peer: &str) -> Result<(), Box<dyn Error>> {
+        let peer = peer.to_string();
+        self.expect_ok(Request::AddPeer { peer }).await
+    }
+
+    pub async fn remove_peer(
+        &mut self,
+        peer: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let peer = peer.to_string();
+        self.expect_ok(Request::RemovePeer { peer }).await
+    }
+
+    // No alias removes it
+    pub async fn set_alias(
+        &mut self,
+        peer: &str,
+        alias: Option<&str>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.expect_ok(Request::SetAlias {
+            peer: peer.to_string(),
+            alias: alias.map(str::to_string),
+        })
+        .await
+    }
+
+    pub async fn set_policy(
+        &mut self,
+        peer: &str,
+        policy: PeerPolicy,
+    ) -> Result<(), Box<dyn Error>> {
+        self.expect_ok(Request::SetPolicy {
+            peer: peer.to_string(),
+            policy,
+        })
+        .await
+    }
+
+    // Dial peer outside of local network
+    pub async fn dial(&mut self, addr: &str) -> Result<(), Box<dyn Error>> {
+        let addr = addr.to_string();
+        self.expect_ok(Request::Connect { addr }).await
+    }
+
+    pub async fn pair_uri(&mut self) -> Result<String, Box<dyn Error>> {
+        match self.request(Request::PairUri).await? {
+            Response::PairUri(uri) => Ok(uri),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub async fn pair_redeem(
+        &mut self,
+        uri: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let uri = uri.to_string();
+        self.expect_ok(Request::PairRedeem { uri }).await
+    }
+
+    pub async fn pairings(
+        &mut self,
+    ) -> Result<Vec<PairingSummary>, Box<dyn Error>> {
+        match self.request(Request::Pairings).await? {
+            Response::Pairings(pairings) => Ok(pairings),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub async fn pair_confirm(
+        &mut self,
+        peer: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let peer = peer.to_string();
+        self.expect_ok(Request::PairConfirm { peer }).await
+    }
+
+    pub async fn pair_reject(
+        &mut self,
+        peer: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let peer = peer.to_string();
+        self.expect_ok(Request::PairReject { peer }).await
+    }
+
+    // Fingerprint of swarm key, None outside of private network
+    pub async fn swarm_key(
+        &mut self,
+    ) -> Result<Option<String>, Box<dyn Error>> {
+        match self.request(Request::SwarmKey).await? {
+            Response::SwarmKey(fingerprint) => Ok(fingerprint),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    // Key is used once node is restarted, returns its fingerprint
+    pub async fn generate_swarm_key(
+        &mut self,
+    ) -> Result<String, Box<dyn Error>> {
+        match self.request(Request::GenerateSwarmKey).await? {
+            Response::SwarmKey(Some(fingerprint)) => Ok(fingerprint),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    // Whole history, or entries matching query
+    pub async fn history(
+        &mut self,
+        query: Option<&str>,
+    ) -> Result<Vec<HistorySummary>, Box<dyn Error>> {
+        let query = query.map(str::to_string);
+        match self.request(Request::History { query }).await? {
+            Response::History(entries) => Ok(entries),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    // Text of entry, preview for binary content
+    pub async fn history_show(
+        &mut self,
+        id: u64,
+    ) -> Result<String, Box<dyn Error>> {
+        match self.request(Request::HistoryShow { id }).await? {
+            Response::Text(text) => Ok(text),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub async fn history_apply(
+        &mut self,
+        id: u64,
+    ) -> Result<(), Box<dyn Error>> {
+        self.expect_ok(Request::HistoryApply { id }).await
+    }
+
+    // Put text on local clipboard and share it with peers
+    pub async fn clip_send(
+        &mut self,
+        text: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let text = text.to_string();
+        self.expect_ok(Request::ClipSend { text }).await
+    }
+
+    // Text on local clipboard, description for binary content
+    pub async fn clip_get(&mut self) -> Result<String, Box<dyn Error>> {
+        match self.request(Request::ClipGet).await? {
+            Response::Text(text) => Ok(text),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub async fn pause(
+        &mut self,
+        direction: Direction,
+    ) -> Result<(), Box<dyn Error>> {
+        self.expect_ok(Request::Pause { direction }).await
+    }
+
+    pub async fn resume(
+        &mut self,
+        direction: Direction,
+    ) -> Result<(), Box<dyn Error>> {
+        self.expect_ok(Request::Resume { direction }).await
+    }
+
+    pub async fn sync_status(&mut self) -> Result<SyncStatus, Box<dyn Error>> {
+        match self.request(Request::SyncStatus).await? {
+            Response::SyncStatus(status) => Ok(status),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    // Connection carries only events afterwards, so client is consumed
+    pub async fn subscribe(mut self) -> Result<Events, Box<dyn Error>> {
+        if let Connection::Udp(_) = self.connection {
+            return Err(NO_EVENTS.into());
+        }
+        let id = self.next_id;
+        self.expect_ok(Request::Subscribe).await?;
+        Ok(Events {
+            connection: self.connection,
+            id,
+        })
+    }
+}
+
+// Events of node, in the order they happened
+pub struct Events {
+    connection: Connection,
+    // Id of subscribe request, node sends events with it
+    id: u64,
+}
+
+impl Events {
+    // None once node has closed connection
+    pub async fn next(&mut self) -> Result<Option<Event>, Box<dyn Error>> {
+        match &mut self.connection {
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            Connection::Socket(stream) => {
+                let Some(frame) = read_frame(stream).await? else {
+                    return Ok(None);
+                };
+                let frame: ResponseFrame = serde_json::from_slice(&frame)?;
+                if frame.id != self.id {
+                    return Err(format!(
+                        "Node sent frame {} instead of event",
+                        frame.id
+                    )
+                    .into());
+                }
+                match frame.result? {
+                    Response::Event(event) => Ok(Some(event)),
+                    response => Err(unexpected(response)),
+                }
+            }
+            Connection::Udp(_) => Err(NO_EVENTS.into()),
+            Connection::Closed => Err(CLOSED.into()),
+        }
+    }
+}
+
+// Port desktop node listens on for udp requests, saved to its data.json
+pub fn udp_port() -> Result<u16, Box<dyn Error>> {
+    let data = fs::read_to_string(app_dir()?.join("data.json"))
+        .map_err(|_| NOT_RUNNING)?;
+    let data: Map<String, Value> = serde_json::from_str(&data)?;
+    let port = data
+        .get("port")
+        .and_then(Value::as_str)
+        .and_then(|port| port.parse().ok())
+        .ok_or(NOT_RUNNING)?;
+    Ok(port)
+}
+
+// Node answered with response to a different kind of request
+fn unexpected(response: Response) -> Box<dyn Error> {
+    format!("Unexpected response from node: {response:?}").into()
+}
@@ -0,0 +1,261 @@
+// Tracking of clipboard values shared with peers
+use image::ImageFormat;
+use libp2p::PeerId;
+use resk_proto::{Direction, SyncStatus};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+use crate::payload::{
+    content_hash, ClipboardUpdate, LEGACY_VERSION, MIME_PNG, MIME_TEXT,
+};
+use crate::utils::unix_millis;
+
+// Value that can be shared between clipboards
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClipboardContent {
+    Text(String),
+    // Rich text with plain text fallback
+    Html { html: String, text: String },
+    // PNG encoded image
+    Image(Vec<u8>),
+}
+
+impl ClipboardContent {
+    pub fn mime(&self) -> &'static str {
+        match self {
+            ClipboardContent::Text(_) | ClipboardContent::Html { .. } => {
+                MIME_TEXT
+            }
+            ClipboardContent::Image(_) => MIME_PNG,
+        }
+    }
+
+    // Most basic representation of content
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            ClipboardContent::Text(text) => text.as_bytes(),
+            ClipboardContent::Html { text, .. } => text.as_bytes(),
+            ClipboardContent::Image(png) => png,
+        }
+    }
+
+    // Plain text of content, if it has any
+    pub fn text(&self) -> Option<&str> {
+        match self {
+            ClipboardContent::Text(text) => Some(text),
+            ClipboardContent::Html { text, .. } => Some(text),
+            ClipboardContent::Image(_) => None,
+        }
+    }
+
+    // Hash that stays the same after content went through a clipboard
+    // Images are re-encoded by clipboards, so only pixels are hashed
+    // Html markup may be rewritten as well, so only its text is hashed
+    pub fn fingerprint(&self) -> String {
+        if let ClipboardContent::Image(png) = self {
+            if let Ok(image) =
+                image::load_from_memory_with_format(png, ImageFormat::Png)
+            {
+                let image = image.to_rgba8();
+                let mut hasher = Sha256::new();
+                hasher.update(image.width().to_be_bytes());
+                hasher.update(image.height().to_be_bytes());
+                hasher.update(image.as_raw());
+                return hex::encode(hasher.finalize());
+            }
+        }
+        content_hash(self.as_bytes())
+    }
+}
+
+// Where the value currently on the clipboard came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClipboardOrigin {
+    // Copied on this device
+    Local,
+    // Applied from an update received from a peer
+    Remote,
+}
+
+// Last known clipboard value and its origin
+// Used to keep values applied from peers from being echoed back to the mesh
+pub struct ClipboardState {
+    fingerprint: String,
+    origin: ClipboardOrigin,
+    // Sequence number of the last local update
+    // Starts from current time so it keeps growing across restarts
+    seq: u64,
+    // Last sequence number applied from every origin peer
+    // Keyed by authenticated peer id, never by what updates claim
+    last_seq: HashMap<PeerId, u64>,
+}
+
+impl ClipboardState {
+    pub fn new(content: Option<&ClipboardContent>) -> Self {
+        ClipboardState {
+            fingerprint: content
+                .map(|content| content.fingerprint())
+                .unwrap_or_default(),
+            origin: ClipboardOrigin::Local,
+            seq: unix_millis(),
+            last_seq: HashMap::new(),
+        }
+    }
+
+    pub fn next_seq(&mut self) -> u64 {
+        self.seq += 1;
+        self.seq
+    }
+
+    // Check update received from a peer before its content is applied
+    // Returns false for stale updates and duplicates of the current value
+    pub fn accept_remote(
+        &mut self,
+        author: &PeerId,
+        update: &ClipboardUpdate,
+    ) -> bool {
+        // Legacy messages carry no sequence number, they only come as raw
+        // gossipsub text, which gossipsub itself does not deliver twice
+        if update.version != LEGACY_VERSION {
+            if let Some(last_seq) = self.last_seq.get(author) {
+                if update.seq <= *last_seq {
+                    log::info!(
+                        "Skipping stale clipboard update {} from {author}",
+                        update.seq
+                    );
+                    return false;
+                }
+            }
+            self.last_seq.insert(*author, update.seq);
+        }
+        if self.is_duplicate(update) {
+            log::debug!("Skipping duplicate clipboard update");
+            return false;
+        }
+        true
+    }
+
+    // Update carries the value that is already on the clipboard
+    // Payload hash can not be used, images are re-encoded by clipboards
+    fn is_duplicate(&self, update: &ClipboardUpdate) -> bool {
+        update
+            .content()
+            .is_some_and(|content| content.fingerprint() == self.fingerprint)
+    }
+
+    // Record value that was put on the clipboard from a peer update
+    pub fn set_remote(&mut self, content: &ClipboardContent) {
+        self.fingerprint = content.fingerprint();
+        self.origin = ClipboardOrigin::Remote;
+    }
+
+    // Record value detected by the clipboard watcher
+    // Returns true only for genuine local edits that should be broadcast
+    pub fn observe_local(&mut self, content: &ClipboardContent) -> bool {
+        let fingerprint = content.fingerprint();
+        if fingerprint == self.fingerprint {
+            if self.origin == ClipboardOrigin::Remote {
+                log::debug!("Skipping clipboard value applied from peer");
+            }
+            return false;
+        }
+        self.fingerprint = fingerprint;
+        self.origin = ClipboardOrigin::Local;
+        true
+    }
+}
+
+// Directions of sharing paused by user, e.g. while copying passwords
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct SyncPause {
+    // Updates from peers are not applied
+    #[serde(default)]
+    pub incoming: bool,
+    // Local edits are not published
+    #[serde(default)]
+    pub outgoing: bool,
+}
+
+impl SyncPause {
+    pub fn set(&mut self, direction: Direction, paused: bool) {
+        match direction {
+            Direction::Both => {
+                self.incoming = paused;
+                self.outgoing = paused;
+            }
+            Direction::Incoming => self.incoming = paused,
+            Direction::Outgoing => self.outgoing = paused,
+        }
+    }
+
+    pub fn status(&self) -> SyncStatus {
+        SyncStatus {
+            incoming: self.incoming,
+            outgoing: self.outgoing,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(text: &str) -> ClipboardContent {
+        ClipboardContent::Text(text.to_string())
+    }
+
+    fn update(author: &PeerId, seq: u64, content: &str) -> ClipboardUpdate {
+        ClipboardUpdate::new(author, seq, &text(content))
+    }
+
+    #[test]
+    fn applied_value_is_not_echoed() {
+        let mut state = ClipboardState::new(Some(&text("old")));
+        let peer = PeerId::random();
+        let remote = update(&peer, 1, "from peer");
+        assert!(state.accept_remote(&peer, &remote));
+        state.set_remote(&remote.content().unwrap());
+
+        // Watcher sees the applied value, it must not go back to the mesh
+        assert!(!state.observe_local(&text("from peer")));
+        assert!(state.observe_local(&text("copied here")));
+        assert!(!state.observe_local(&text("copied here")));
+    }
+
+    #[test]
+    fn current_value_is_not_applied_again() {
+        let mut state = ClipboardState::new(Some(&text("same")));
+        let peer = PeerId::random();
+        assert!(!state.accept_remote(&peer, &update(&peer, 1, "same")));
+        assert!(state.accept_remote(&peer, &update(&peer, 2, "other")));
+    }
+
+    #[test]
+    fn stale_updates_are_rejected() {
+        let mut state = ClipboardState::new(None);
+        let peer = PeerId::random();
+        assert!(state.accept_remote(&peer, &update(&peer, 5, "five")));
+        assert!(!state.accept_remote(&peer, &update(&peer, 5, "replayed")));
+        assert!(!state.accept_remote(&peer, &update(&peer, 4, "older")));
+        assert!(state.accept_remote(&peer, &update(&peer, 6, "newer")));
+    }
+
+    #[test]
+    fn sequence_is_tracked_per_author() {
+        let mut state = ClipboardState::new(None);
+        let (a, b) = (PeerId::random(), PeerId::random());
+        assert!(state.accept_remote(&a, &update(&a, 100, "from a")));
+        assert!(state.accept_remote(&b, &update(&b, 1, "from b")));
+        assert!(!state.accept_remote(&a, &update(&a, 50, "old from a")));
+        assert!(state.accept_remote(&b, &update(&b, 2, "more from b")));
+    }
+
+    #[test]
+    fn local_sequence_grows() {
+        let mut state = ClipboardState::new(None);
+        let first = state.next_seq();
+        assert!(first >= unix_millis() - 1000);
+        assert_eq!(state.next_seq(), first + 1);
+    }
+}
@@ -0,0 +1,79 @@
+// User configuration, read from config.json in app dir
+use libp2p::Multiaddr;
+use serde::Deserialize;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Config {
+    // Name advertised to peers, hostname by default
+    pub device_name: Option<String>,
+    // Peers dialed on start on networks mdns does not reach
+    // Address can end with /p2p/<peer id>
+    pub bootstrap: Vec<Multiaddr>,
+    pub history: HistoryConfig,
+    pub filter: FilterConfig,
+    pub relay: RelayConfig,
+    // Also answer status requests on udp port saved in data.json,
+    // the only way to reach node on windows. Always on for mobile bridge
+    pub udp_control: bool,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct HistoryConfig {
+    // Number of entries to keep
+    pub limit: usize,
+    // Total size of kept entries in bytes
+    pub max_size: usize,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            limit: 50,
+            max_size: 16 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct FilterConfig {
+    // Regex patterns of text that is never shared with peers
+    // Replaces default rules when set
+    pub deny: Vec<String>,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        FilterConfig {
+            deny: vec![r"-----BEGIN [A-Z ]*PRIVATE KEY-----".to_string()],
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct RelayConfig {
+    // Relay other nodes, e.g. on an always-on desktop
+    pub server: bool,
+    // Relays to reserve a slot on, must end with /p2p/<relay peer id>
+    pub addrs: Vec<Multiaddr>,
+    // Addresses this node is reachable on from other networks,
+    // e.g. public ip with forwarded port, needed to serve as relay
+    // Nothing else confirms them, there is no autonat
+    pub external_addrs: Vec<Multiaddr>,
+}
+
+// Missing config file means defaults
+pub fn load_config(data_dir: &Path) -> Result<Config, Box<dyn Error>> {
+    let path = data_dir.join("config.json");
+    if !path.exists() {
+        return Ok(Config::default());
+    }
+    let json_str = fs::read_to_string(&path)?;
+    Ok(serde_json::from_str(&json_str)?)
+}
@@ -0,0 +1,374 @@
+// Requests of client apps
+// Desktop apps connect to a unix socket only current user can access,
+// requests and responses are prefixed with their length. Udp listener is
+// kept for the mobile bridge and platforms without unix sockets, it only
+// answers status requests
+// Frames are json encoded messages of resk_proto. Connection that
+// subscribed to events carries nothing else afterwards
+use resk_proto::transport::MAX_DATAGRAM_SIZE;
+use resk_proto::{
+    ErrorCode, Event, ProtoError, Request, RequestFrame, Response,
+    ResponseFrame, PROTOCOL_VERSION,
+};
+use serde_json::Value;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot};
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use {
+    resk_proto::transport::{read_frame, socket_path, write_frame},
+    std::error::Error,
+    std::fs::{self, DirBuilder, Permissions},
+    std::io,
+    std::os::unix::fs::{DirBuilderExt, PermissionsExt},
+    std::path::PathBuf,
+    std::process,
+    tokio::io::{AsyncReadExt, AsyncWrite},
+    tokio::net::{UnixListener, UnixStream},
+    tokio::select,
+    tokio::signal::unix::{signal, SignalKind},
+    tokio::sync::broadcast,
+};
+
+// Request answered by main loop of node
+pub struct ControlRequest {
+    pub request: Request,
+    pub respond: oneshot::Sender<Result<Response, ProtoError>>,
+}
+
+// Removes socket file once node stops, so it is not left behind
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub struct SocketGuard {
+    path: PathBuf,
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+impl Drop for SocketGuard {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.path) {
+            log::error!("Failed to remove {}: {err}", self.path.display());
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub async fn start_socket_listener(
+    sender: mpsc::Sender<ControlRequest>,
+    events: broadcast::Sender<Event>,
+) -> Result<SocketGuard, Box<dyn Error>> {
+    let path = socket_path()?;
+    if let Some(dir) = path.parent() {
+        DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+        // Directory may have been created before with wider permissions
+        fs::set_permissions(dir, Permissions::from_mode(0o700))?;
+    }
+    if path.exists() {
+        // Socket of a node that is still running must not be taken over
+        if UnixStream::connect(&path).await.is_ok() {
+            return Err(format!(
+                "Node is already running on {}",
+                path.display()
+            )
+            .into());
+        }
+        fs::remove_file(&path)?;
+    }
+    // Bound under temporary name and moved in place once only owner can
+    // connect, so there is no moment the socket is open to others
+    let tmp_path = path.with_extension(format!("{}.tmp", process::id()));
+    let _ = fs::remove_file(&tmp_path);
+    let listener = UnixListener::bind(&tmp_path)?;
+    fs::set_permissions(&tmp_path, Permissions::from_mode(0o600))?;
+    fs::rename(&tmp_path, &path)?;
+    let guard = SocketGuard { path: path.clone() };
+    log::info!(
+        "Waiting for messages from client apps on {}",
+        path.display()
+    );
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let events = events.clone();
+                    tokio::spawn(serve_stream(stream, sender.clone(), events));
+                }
+                Err(err) => log::error!("Failed to accept client app: {err}"),
+            }
+        }
+    });
+    Ok(guard)
+}
+
+// Client app can send any number of requests over one connection
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+async fn serve_stream(
+    mut stream: UnixStream,
+    sender: mpsc::Sender<ControlRequest>,
+    events: broadcast::Sender<Event>,
+) {
+    loop {
+        let request = match read_frame(&mut stream).await {
+            Ok(Some(request)) => request,
+            Ok(None) => return,
+            Err(err) => {
+                log::error!("Failed to read request from client app: {err}");
+                return;
+            }
+        };
+        let response = match parse_request(&request) {
+            Ok(RequestFrame {
+                id,
+                request: Request::Subscribe,
+                ..
+            }) => {
+                return stream_events(stream, id, events.subscribe()).await;
+            }
+            Ok(frame) => match forward(&sender, frame).await {
+                Some(response) => response,
+                None => return,
+            },
+            Err(response) => response,
+        };
+        if let Err(err) = send_frame(&mut stream, &response).await {
+            log::error!("Failed to answer client app: {err}");
+            return;
+        }
+    }
+}
+
+// Events are sent until client app closes connection
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+async fn stream_events(
+    mut stream: UnixStream,
+    id: u64,
+    mut events: broadcast::Receiver<Event>,
+) {
+    let subscribed = ResponseFrame::new(id, Ok(Response::Ok));
+    if let Err(err) = send_frame(&mut stream, &subscribed).await {
+        log::error!("Failed to answer client app: {err}");
+        return;
+    }
+    let (mut reader, mut writer) = stream.split();
+    loop {
+        let event = select! {
+            event = events.recv() => event,
+            // Subscribed client app sends nothing, so it is gone
+            _ = reader.read_u8() => return,
+        };
+        let event = match event {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                log::error!("Client app is too slow, {missed} events dropped");
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        let event = ResponseFrame::new(id, Ok(Response::Event(event)));
+        if let Err(err) = send_frame(&mut writer, &event).await {
+            log::debug!("Failed to send event to client app: {err}");
+            return;
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+async fn send_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    response: &ResponseFrame,
+) -> io::Result<()> {
+    write_frame(writer, &serde_json::to_vec(response)?).await
+}
+
+// Resolves once node is asked to stop, never if signals can not be watched
+pub async fn shutdown_signal() {
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(err) => {
+                log::error!("Failed to watch for termination: {err}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => {
+            if let Err(err) = result {
+                log::error!("Failed to watch for ctrl-c: {err}");
+                std::future::pending::<()>().await;
+            }
+        }
+        _ = terminate => {}
+    }
+}
+
+pub async fn serve_udp(
+    socket: UdpSocket,
+    sender: mpsc::Sender<ControlRequest>,
+) {
+    let mut buf: Vec<u8> = Vec::with_capacity(MAX_DATAGRAM_SIZE);
+    loop {
+        buf.clear();
+        let addr = match socket.recv_buf_from(&mut buf).await {
+            Ok((_, addr)) => addr,
+            Err(err) => {
+                log::error!("Error obtaining request from client app: {err:?}");
+                continue;
+            }
+        };
+        let response = match parse_request(&buf) {
+            Ok(frame) if !served_over_udp(&frame.request) => {
+                let err = ProtoError::new(
+                    ErrorCode::NotAllowed,
+                    "Request is only served over control socket",
+                );
+                ResponseFrame::new(frame.id, Err(err))
+            }
+            Ok(frame) => match forward(&sender, frame).await {
+                Some(response) => response,
+                None => return,
+            },
+            Err(response) => response,
+        };
+        let response = match encode_datagram(response) {
+            Ok(response) => response,
+            Err(err) => {
+                log::error!("Failed to encode response: {err}");
+                continue;
+            }
+        };
+        if let Err(err) = socket.send_to(&response, addr).await {
+            log::error!("Failed to answer client app: {err}");
+        }
+    }
+}
+
+// Any local user or process can send datagrams, so they only get status
+// Clipboard, history, pairing and trust stay behind the socket
+fn served_over_udp(request: &Request) -> bool {
+    match request {
+        Request::IsAlive
+        | Request::GetPeers
+        | Request::Peers
+        | Request::Pairings
+        | Request::SwarmKey
+        | Request::LocalPeerId
+        | Request::SyncStatus => true,
+        Request::AddPeer { .. }
+        | Request::RemovePeer { .. }
+        | Request::SetAlias { .. }
+        | Request::SetPolicy { .. }
+        | Request::Connect { .. }
+        | Request::PairUri
+        | Request::PairRedeem { .. }
+        | Request::PairConfirm { .. }
+        | Request::PairReject { .. }
+        | Request::GenerateSwarmKey
+        | Request::History { .. }
+        | Request::HistoryShow { .. }
+        | Request::HistoryApply { .. }
+        | Request::ClipSend { .. }
+        | Request::ClipGet
+        | Request::Pause { .. }
+        | Request::Resume { .. }
+        | Request::Subscribe => false,
+    }
+}
+
+// Frames that can not be handled are answered right away
+fn parse_request(frame: &[u8]) -> Result<RequestFrame, ResponseFrame> {
+    let frame: RequestFrame = match serde_json::from_slice(frame) {
+        Ok(frame) => frame,
+        Err(err) => {
+            // Id is still echoed if it can be read
+            let id = serde_json::from_slice::<Value>(frame)
+                .ok()
+                .and_then(|frame| frame["id"].as_u64())
+                .unwrap_or_default();
+            let err =
+                ProtoError::new(ErrorCode::InvalidRequest, err.to_string());
+            return Err(ResponseFrame::new(id, Err(err)));
+        }
+    };
+    if frame.version != PROTOCOL_VERSION {
+        let err = ProtoError::new(
+            ErrorCode::UnsupportedVersion,
+            format!(
+                "Protocol version {} is not supported, node speaks {PROTOCOL_VERSION}",
+                frame.version
+            ),
+        );
+        return Err(ResponseFrame::new(frame.id, Err(err)));
+    }
+    Ok(frame)
+}
+
+// None once node has stopped
+async fn forward(
+    sender: &mpsc::Sender<ControlRequest>,
+    frame: RequestFrame,
+) -> Option<ResponseFrame> {
+    let (respond, result) = oneshot::channel();
+    sender
+        .send(ControlRequest {
+            request: frame.request,
+            respond,
+        })
+        .await
+        .ok()?;
+    Some(ResponseFrame::new(frame.id, result.await.ok()?))
+}
+
+// Responses that do not fit in a datagram are replaced with an error
+fn encode_datagram(response: ResponseFrame) -> serde_json::Result<Vec<u8>> {
+    let encoded = serde_json::to_vec(&response)?;
+    if encoded.len() <= MAX_DATAGRAM_SIZE {
+        return Ok(encoded);
+    }
+    let err = ProtoError::new(
+        ErrorCode::TooLarge,
+        format!(
+            "Response of {} bytes does not fit in a datagram",
+            encoded.len()
+        ),
+    );
+    serde_json::to_vec(&ResponseFrame::new(response.id, Err(err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn udp_serves_only_status() {
+        assert!(served_over_udp(&Request::IsAlive));
+        assert!(served_over_udp(&Request::SyncStatus));
+        assert!(!served_over_udp(&Request::ClipGet));
+        assert!(!served_over_udp(&Request::HistoryShow { id: 1 }));
+        assert!(!served_over_udp(&Request::PairUri));
+        assert!(!served_over_udp(&Request::AddPeer {
+            peer: "laptop".to_string()
+        }));
+    }
+
+    #[test]
+    fn answers_broken_frames_with_their_id() {
+        let response = parse_request(br#"{"version":1,"id":5}"#).unwrap_err();
+        assert_eq!(response.id, 5);
+        assert_eq!(
+            response.result.unwrap_err().code,
+            ErrorCode::InvalidRequest
+        );
+
+        let frame = br#"{"version":9,"id":6,"method":"is_alive"}"#;
+        let response = parse_request(frame).unwrap_err();
+        assert_eq!(response.id, 6);
+        assert_eq!(
+            response.result.unwrap_err().code,
+            ErrorCode::UnsupportedVersion
+        );
+    }
+}
@@ -3,37 +3,74 @@
     target_os = "windows",
     target_os = "macos"
 ))]
-use clipboard::{ClipboardContext, ClipboardProvider};
+use crate::desktop::DesktopClipboard;
 
 #[cfg(any(target_os = "android", target_os = "ios"))]
 use crate::mobile::MobileClipboard;
 #[cfg(any(target_os = "android", target_os = "ios"))]
 use crate::utils::send_udp_msg_flutter;
+#[cfg(any(
+    target_os = "linux",
+    target_os = "windows",
+    target_os = "macos"
+))]
+use resk_proto::transport::app_dir;
 
 use futures::{future::Either, StreamExt};
 use libp2p::core::transport;
 use libp2p::kad::store::MemoryStore;
-use libp2p::kad::Kademlia;
-use libp2p::Multiaddr;
+use libp2p::kad::{self, Kademlia};
 use libp2p::{
     core::{muxing::StreamMuxerBox, transport::OrTransport, upgrade},
-    gossipsub,
+    dcutr, gossipsub, identify,
     identity::Keypair,
-    mdns, noise, quic,
-    swarm::{NetworkBehaviour, SwarmBuilder, SwarmEvent},
+    mdns, noise,
+    pnet::{PnetConfig, PreSharedKey},
+    quic, relay, request_response,
+    swarm::{
+        behaviour::toggle::Toggle,
+        dial_opts::{DialOpts, PeerCondition},
+        NetworkBehaviour, Swarm, SwarmBuilder, SwarmEvent,
+    },
     tcp, yamux, PeerId, Transport,
 };
-use std::collections::HashSet;
+use libp2p::{multiaddr::Protocol, Multiaddr};
+use resk_proto::{ErrorCode, Event, OnlinePeer, ProtoError, Request, Response};
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::path::PathBuf;
 use std::str::FromStr;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::select;
-use tokio::sync::mpsc::{self, Sender};
-use tokio::time::{sleep, Duration};
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::{interval, Duration};
 
+use crate::clipboard_sync::{ClipboardContent, ClipboardState, SyncPause};
+use crate::config::load_config;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use crate::control::start_socket_listener;
+use crate::control::{serve_udp, shutdown_signal, ControlRequest};
+use crate::dialer::{
+    addr_peer_id, is_bootstrap_addr, Redials, BOOTSTRAP_INTERVAL, KAD_PROTOCOL,
+    REDIAL_INTERVAL,
+};
+use crate::filter::ClipboardFilter;
+use crate::history::ClipboardHistory;
+use crate::pairing::{
+    PairRequest, PairResponse, PairingUri, Pairings, PAIRING_PROTOCOL,
+};
+use crate::payload::{
+    ClipboardUpdate, PushResponse, TransferCodec, CLIPBOARD_PROTOCOL,
+    MAX_TRANSFER_SIZE, UPDATE_TOPIC,
+};
+use crate::peers::{
+    device_name, DeviceInfo, PeerRecord, PeerStore, TrustedPeers, INFO_PROTOCOL,
+};
+use crate::swarm_key::{generate_swarm_key, load_swarm_key};
 use crate::utils::{
-    get_keys, init_backend_listener, load_known_peers, save_peer,
+    get_keys, init_backend_listener, load_sync_pause, save_sync_pause,
 };
+use crate::watcher::{start_watching_clipboard, LocalCopy};
 
 #[macro_export]
 macro_rules! desktop {
@@ -105,37 +142,93 @@ pub async fn run_node(
     println!("Local peer id: {}", &local_peer_id.to_string());
     log::info!("Local peer id: {}", &local_peer_id.to_string());
 
-    // Listener for sending data to client apps
-    desktop! {
-        let backend_listener = init_backend_listener().await?
-    }
-    mobile! {
-        let backend_listener = init_backend_listener(app_dir_path.unwrap()).await?
+    // Directory with node data
+    let data_dir: PathBuf;
+    desktop!({
+        data_dir = app_dir()?;
+    });
+    mobile!({
+        data_dir =
+            PathBuf::from(app_dir_path.clone().unwrap().trim_matches('\0'));
+    });
+    let config = load_config(&data_dir)?;
+
+    // Requests from client apps, answered in main loop
+    let (control_sender, mut control_receiver) = mpsc::channel(64);
+    // Events for subscribed client apps, slow ones miss the oldest
+    let (events, _) = broadcast::channel(256);
+    // Socket file is removed once run_node returns
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    let _socket =
+        start_socket_listener(control_sender.clone(), events.clone()).await?;
+    // Udp is opt-in, except for mobile bridge that has nothing else
+    // It is open to every local user, so only status is served over it
+    if config.udp_control || cfg!(any(target_os = "android", target_os = "ios"))
+    {
+        desktop! {
+            let backend_listener = init_backend_listener().await?
+        }
+        mobile! {
+            let backend_listener = init_backend_listener(app_dir_path.unwrap()).await?
+        }
+        tokio::spawn(serve_udp(backend_listener, control_sender));
+    } else if cfg!(target_os = "windows") {
+        log::info!("Client apps can not reach node, udp_control is off");
     }
 
     // Clipboard management
     desktop! {
-        let mut clipboard: ClipboardContext =
-            ClipboardProvider::new().expect("Failed to init clipboard")
+        let mut clipboard: PlatformClipboard =
+            DesktopClipboard::new().expect("Failed to init clipboard")
     };
     mobile! {
-        let mut clipboard: MobileClipboard =
+        let mut clipboard: PlatformClipboard =
             MobileClipboard::new(flutter_udp_port.clone().unwrap())
                 .expect("Failed to init clipboard")
 
     };
 
+    // Track origin of clipboard content to avoid echoing peer updates
+    let mut clipboard_state =
+        ClipboardState::new(clipboard.get_content().unwrap_or(None).as_ref());
+    // Sharing can be paused from client apps per direction
+    let mut sync_pause = load_sync_pause(&data_dir).unwrap_or_else(|err| {
+        log::error!("Failed to load sync state: {err}");
+        SyncPause::default()
+    });
+    // Keeps sensitive content from being shared
+    let clipboard_filter = ClipboardFilter::new(&config.filter)?;
+    let mut history = ClipboardHistory::load(
+        data_dir.join("history.json"),
+        config.history.clone(),
+    );
+    history.spawn_writer();
+
     // Communications betwen threads
-    // let (sender, mut receiver) = mpsc::channel(8192);
+    let (sender, mut receiver) = mpsc::channel(8192);
 
     // Store active peers
     let mut peers_online: Vec<(String, String)> = vec![];
     let mut peers_online_system: Vec<(PeerId, Multiaddr)> = vec![];
 
-    let transport = build_transport(&local_key).await?;
+    // Trusted peers with their names and policies
+    let mut peer_store = PeerStore::load(&data_dir)?;
+
+    // Peers on other networks are reached through relays
+    let (relay_transport, relay_client) = relay::client::new(local_peer_id);
+    // Only devices holding the same key can connect in private network
+    let swarm_key = load_swarm_key(&data_dir)?;
+    if let Some(key) = swarm_key {
+        log::info!(
+            "Private network with key fingerprint {}",
+            key.fingerprint()
+        );
+    }
+    let transport =
+        build_transport(&local_key, relay_transport, swarm_key).await?;
 
-    // Topic used to send clipboard updates
-    let update_topic = gossipsub::IdentTopic::new("resk-update");
+    // Topic where older nodes send clipboard updates
+    let update_topic = gossipsub::IdentTopic::new(UPDATE_TOPIC);
 
     // Build swarm
     let mut swarm = {
@@ -145,10 +238,26 @@ pub async fn run_node(
         mdns_config.ttl = Duration::from_secs(60);
         let mdns = mdns::tokio::Behaviour::new(mdns_config, local_peer_id)?;
 
+        // Tells peers which addresses they are seen on, needed for hole punching
+        let identify = identify::Behaviour::new(identify::Config::new(
+            "/resk/1.0.0".to_string(),
+            local_key.public(),
+        ));
+        // Upgrades relayed connections to direct ones
+        let dcutr = dcutr::Behaviour::new(local_peer_id);
+        let relay_server = Toggle::from(config.relay.server.then(|| {
+            relay::Behaviour::new(
+                local_peer_id,
+                relay_config(peer_store.trusted_peers()),
+            )
+        }));
+
         // gossipsub config
         let gossipsub_config = gossipsub::ConfigBuilder::default()
             .heartbeat_interval(Duration::from_secs(10))
             .validation_mode(gossipsub::ValidationMode::Strict)
+            // Messages are forwarded only after they were checked in run_node
+            .validate_messages()
             .build()?;
         let mut gossipsub = gossipsub::Behaviour::new(
             gossipsub::MessageAuthenticity::Signed(local_key),
@@ -160,181 +269,914 @@ pub async fn run_node(
 
         // kademlia config
         let store = MemoryStore::new(local_peer_id);
-        let kademlia = Kademlia::new(local_peer_id, store);
+        let mut kademlia_config = kad::Config::default();
+        kademlia_config.set_protocol_names(vec![KAD_PROTOCOL]);
+        let mut kademlia =
+            Kademlia::with_config(local_peer_id, store, kademlia_config);
+        // External addresses come only from config, there is no autonat,
+        // peers on the same network can answer queries anyway
+        kademlia.set_mode(Some(kad::Mode::Server));
+
+        // Clipboard updates are pushed to every trusted peer directly
+        let mut transfer_config = request_response::Config::default();
+        transfer_config.set_request_timeout(Duration::from_secs(60));
+        let clipboard_transfer = request_response::Behaviour::with_codec(
+            TransferCodec::new(peer_store.trusted_peers()),
+            [(CLIPBOARD_PROTOCOL, request_response::ProtocolSupport::Full)],
+            transfer_config,
+        );
+
+        // Pairing handshake, users may take a while to compare codes
+        let mut pairing_config = request_response::Config::default();
+        pairing_config.set_request_timeout(Duration::from_secs(60));
+        let pairing = request_response::json::Behaviour::new(
+            [(PAIRING_PROTOCOL, request_response::ProtocolSupport::Full)],
+            pairing_config,
+        );
+
+        // Device info is exchanged once peers connect
+        let device_info = request_response::json::Behaviour::new(
+            [(INFO_PROTOCOL, request_response::ProtocolSupport::Full)],
+            request_response::Config::default(),
+        );
+
         let behaviour = Behaviour {
             mdns,
             gossipsub,
             kademlia,
+            clipboard_transfer,
+            pairing,
+            device_info,
+            identify,
+            relay_client,
+            relay_server,
+            dcutr,
         };
         SwarmBuilder::with_tokio_executor(transport, behaviour, local_peer_id)
             .build()
     };
 
-    swarm.listen_on("/ip4/0.0.0.0/udp/0/quic-v1".parse()?)?;
+    // Quic does its own handshake, so it can not be used with swarm key
+    if swarm_key.is_none() {
+        swarm.listen_on("/ip4/0.0.0.0/udp/0/quic-v1".parse()?)?;
+    }
     swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
 
-    // Pooling to share clipboard
-    /*     tokio::spawn(start_pooling_clipboard(
-        sender.clone(),
-        flutter_udp_port.clone(),
-    )); */
+    // Advertised to peers and handed out by relay server
+    for addr in config.relay.external_addrs.iter() {
+        swarm.add_external_address(addr.clone());
+    }
+
+    // Slots on relays are reserved by redial timer,
+    // so peers on other networks can dial this node
+    for addr in config.relay.addrs.iter() {
+        let mut relay_addr = addr.clone();
+        if let Some(Protocol::P2p(peer_id)) = relay_addr.pop() {
+            swarm
+                .behaviour_mut()
+                .kademlia
+                .add_address(&peer_id, relay_addr);
+        }
+    }
+
+    // Static peers from config, dialed by redial timer
+    for addr in config.bootstrap.iter() {
+        let mut peer_addr = addr.clone();
+        if let Some(Protocol::P2p(peer_id)) = peer_addr.pop() {
+            swarm
+                .behaviour_mut()
+                .kademlia
+                .add_address(&peer_id, peer_addr);
+        }
+    }
+
+    // Watch clipboard to share it
+    tokio::spawn(start_watching_clipboard(sender.clone(), flutter_udp_port));
+
+    // Pairings with peers that are not trusted yet
+    let mut pairings = Pairings::new(local_peer_id);
+    // Advertised to peers on connect
+    let device_info = DeviceInfo {
+        name: device_name(config.device_name.as_ref()),
+    };
+    // Peers removed on either side, disconnected once Unpair was answered
+    let mut unpairing: HashSet<PeerId> = HashSet::new();
+    // Offline trusted peers are dialed directly, not only when mdns finds them
+    let mut redials = Redials::new();
+    let mut redial_timer = interval(REDIAL_INTERVAL);
+    // Bootstrap addresses are kept connected the same way, trusted or not
+    let mut bootstrap_redials = Redials::new();
+    // Peers behind bootstrap addresses, known once they connected
+    let mut bootstrap_peers: HashMap<Multiaddr, PeerId> = HashMap::new();
+    // Listeners on relays, reopened with backoff once they close
+    let mut relay_redials = Redials::new();
+    let mut relay_listeners: HashMap<transport::ListenerId, Multiaddr> =
+        HashMap::new();
+    // Trusted peers that are looked up in kademlia after failed dial
+    let mut lookups: HashSet<PeerId> = HashSet::new();
+    let mut bootstrap_timer = interval(BOOTSTRAP_INTERVAL);
+    for record in peer_store.records() {
+        for addr in record.addrs.iter() {
+            swarm
+                .behaviour_mut()
+                .kademlia
+                .add_address(&record.peer_id, addr.clone());
+        }
+    }
 
-    // Load known peers
-    let known_peers: Vec<PeerId> = load_known_peers()?;
+    // Stops the node on ctrl-c or termination
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
 
     // Main pool
     // cloning swarm to multiple threads can create a mess
-    let mut buf: Vec<u8> = Vec::new();
     loop {
-        buf.clear();
         select! {
-                    // Listen for requests from client apps
-                    request = backend_listener.recv_buf_from(&mut buf) => match request {
-                        Ok((_, addr)) => {
-                            let request = String::from_utf8_lossy(&buf).to_string();
-                            let mut response = String::new();
-                            match request.split(":").nth(0).unwrap() {
-                                    "is_alive" => {
-                                        response = "1".to_string();
+            _ = &mut shutdown => {
+                log::info!("Shutting down");
+                return Ok(());
+            }
+            // Listen for requests from client apps
+            request = control_receiver.recv() => if let Some(ControlRequest { request, respond }) = request {
+                    let result = match request {
+                            Request::IsAlive => Ok(Response::Ok),
+                            Request::GetPeers => {
+                                let peers = peers_online
+                                    .iter()
+                                    .map(|(peer_id, addr)| OnlinePeer {
+                                        peer_id: peer_id.clone(),
+                                        addr: addr.clone(),
+                                        name: PeerId::from_str(peer_id)
+                                            .ok()
+                                            .and_then(|peer_id| peer_store.display_name(&peer_id)),
+                                    })
+                                    .collect();
+                                Ok(Response::OnlinePeers(peers))
+                            }
+                            Request::AddPeer { peer } => match peer_store.resolve(&peer) {
+                                Ok(peer_id) if peer_store.is_trusted(&peer_id) => Err(invalid_argument("Peer is already trusted")),
+                                // Peer is trusted once both users confirmed pairing code
+                                Ok(peer_id) => match pairings.initiate(peer_id, None) {
+                                    Ok(request) => {
+                                        swarm.behaviour_mut().pairing.send_request(&peer_id, request);
+                                        Ok(Response::Ok)
                                     }
-                                    "get_peers" => {
-                                        peers_online.clone().into_iter().for_each(|(peer_id, addr)| {
-                                            response.push_str(format!("{peer_id}:{addr:?},").as_str())
-                                        });
+                                    Err(err) => Err(failed(err)),
+                                },
+                                Err(err) => Err(unknown_peer(err)),
+                            },
+                            Request::RemovePeer { peer } => match peer_store.resolve(&peer) {
+                                Ok(peer_id) if peer_store.is_trusted(&peer_id) => {
+                                    // Peer is dialed on its last known addresses if it is not connected
+                                    for addr in peer_store.get(&peer_id).map(|record| record.addrs.clone()).unwrap_or_default() {
+                                        swarm.behaviour_mut().pairing.add_address(&peer_id, addr);
                                     }
-                                    "add_peer" => {
-                                        let peer_id = request.split(":").nth(1).unwrap();
-                                        let peer_id: PeerId = peers_online_system
-                                            .clone()
-                                            .into_iter()
-                                            .filter(|chunk| chunk.0.to_string() == peer_id)
-                                            .nth(0).unwrap().0;
-                                        swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
-                                        save_peer(&peer_id).unwrap_or_else(|err| {log::error!("{err}")});
-                                        response.push_str("OK");
+                                    forget_peer(&mut swarm, &mut peer_store, peer_id);
+                                    // Let peer drop us as well, connection is closed after it answers
+                                    swarm.behaviour_mut().pairing.send_request(&peer_id, PairRequest::Unpair);
+                                    unpairing.insert(peer_id);
+                                    Ok(Response::Ok)
+                                }
+                                Ok(_) => Err(unknown_peer("Unknown peer")),
+                                Err(err) => Err(unknown_peer(err)),
+                            },
+                            Request::SetAlias { peer, alias } => match peer_store.resolve(&peer) {
+                                Ok(peer_id) if peer_store.is_trusted(&peer_id) => {
+                                    let alias = alias.map(|alias| alias.trim().to_string()).filter(|alias| !alias.is_empty());
+                                    if let Some(record) = peer_store.get_mut(&peer_id) {
+                                        record.alias = alias;
                                     }
-                                    "local_peer_id" => {
-                                        response.push_str(&local_peer_id.to_string());
+                                    save_peers(&peer_store);
+                                    Ok(Response::Ok)
+                                }
+                                Ok(_) => Err(unknown_peer("Unknown peer")),
+                                Err(err) => Err(unknown_peer(err)),
+                            },
+                            Request::PairUri => match shareable_addr(&swarm) {
+                                Some(addr) => {
+                                    let token = pairings.new_token();
+                                    Ok(Response::PairUri(PairingUri { peer_id: local_peer_id, addr, token }.to_string()))
+                                }
+                                None => Err(failed("No address to pair with yet")),
+                            },
+                            Request::PairRedeem { uri } => match PairingUri::from_str(&uri) {
+                                Ok(uri) if peer_store.is_trusted(&uri.peer_id) => Err(invalid_argument("Peer is already trusted")),
+                                Ok(uri) => match pairings.initiate(uri.peer_id, Some(uri.token)) {
+                                    Ok(request) => {
+                                        swarm.behaviour_mut().pairing.add_address(&uri.peer_id, uri.addr);
+                                        swarm.behaviour_mut().pairing.send_request(&uri.peer_id, request);
+                                        Ok(Response::Ok)
                                     }
-                                    _ => {
-                                        response.push_str("Incorrect request");},
-
+                                    Err(err) => Err(failed(err)),
+                                },
+                                Err(err) => Err(invalid_argument(err)),
+                            },
+                            Request::Pairings => {
+                                let mut list = pairings.list();
+                                list.iter_mut().for_each(|pairing| {
+                                    pairing.name = PeerId::from_str(&pairing.peer_id)
+                                        .ok()
+                                        .and_then(|peer_id| peer_store.display_name(&peer_id));
+                                });
+                                Ok(Response::Pairings(list))
+                            }
+                            Request::PairConfirm { ref peer } | Request::PairReject { ref peer } => match peer_store.resolve(peer) {
+                                Ok(peer_id) => {
+                                    let pair_request = if matches!(request, Request::PairConfirm { .. }) {
+                                        pairings.confirm(peer_id)
+                                    } else {
+                                        pairings.reject(peer_id)
+                                    };
+                                    match pair_request {
+                                        Ok(pair_request) => {
+                                            swarm.behaviour_mut().pairing.send_request(&peer_id, pair_request);
+                                            for peer_id in pairings.take_completed() {
+                                                trust_peer(&mut swarm, &mut peer_store, peer_id);
+                                            }
+                                            Ok(Response::Ok)
+                                        }
+                                        Err(err) => Err(failed(err)),
                                     }
-                            backend_listener.send_to(response.as_bytes(), addr).await.expect("Failed to ping back from server");
-                        }
-                        Err(e) => {
-                            log::error!("Error obtaining request from client app: {e:?}");
-                        }
-                    },
-                    // Listen for swarm events
-                    event = swarm.select_next_some() => match event {
-                        SwarmEvent::NewListenAddr { address, .. } => log::info!("Listening on {address:?}"),
-                        SwarmEvent::Behaviour(BehaviourEvent::Mdns(mdns::Event::Discovered(peers_list))) => {
-                            // Store active peers
-                            if !peers_list.is_empty(){
-                                peers_online_system.extend(peers_list.clone());
+                                }
+                                Err(err) => Err(unknown_peer(err)),
+                            },
+                            Request::Connect { addr } => match Multiaddr::from_str(&addr) {
+                                Ok(addr) => swarm.dial(addr).map(|()| Response::Ok).map_err(failed),
+                                Err(err) => Err(invalid_argument(err)),
+                            },
+                            Request::SwarmKey => {
+                                Ok(Response::SwarmKey(swarm_key.map(|key| key.fingerprint().to_string())))
+                            }
+                            Request::GenerateSwarmKey => match generate_swarm_key(&data_dir) {
+                                // Used once node is restarted
+                                Ok(key) => Ok(Response::SwarmKey(Some(key.fingerprint().to_string()))),
+                                Err(err) => Err(failed(err)),
+                            },
+                            Request::LocalPeerId => Ok(Response::PeerId(local_peer_id.to_string())),
+                            Request::History { query } => {
+                                let mut list = match query {
+                                    Some(query) => history.search(&query),
+                                    None => history.list(),
+                                };
+                                list.iter_mut().for_each(|summary| {
+                                    summary.origin_name = if summary.origin == local_peer_id.to_string() {
+                                        Some(device_info.name.clone())
+                                    } else {
+                                        PeerId::from_str(&summary.origin)
+                                            .ok()
+                                            .and_then(|peer_id| peer_store.display_name(&peer_id))
+                                    };
+                                });
+                                Ok(Response::History(list))
                             }
-                            let peers_list = filter_incoming_peers(&peers_online, peers_list);
-                            if !peers_list.is_empty() {
-                                if !known_peers.is_empty() {
-                                    let peer_id = PeerId::from_str(&peers_list.clone().into_iter().nth(0).unwrap().0)?;
-                                    if known_peers.contains(&peer_id) {
-                                        swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+                            Request::HistoryShow { id } => match history.get(id) {
+                                Some(entry) => Ok(Response::Text(entry.text().unwrap_or_else(|| entry.summary().preview))),
+                                None => Err(not_found("Unknown history entry")),
+                            },
+                            Request::HistoryApply { id } => match history.get(id) {
+                                Some(entry) => match entry.update.content() {
+                                    // Watcher picks it up and shares it as a local edit
+                                    Some(content) => clipboard.set_content(&content).map(|()| Response::Ok).map_err(failed),
+                                    None => Err(failed("Unsupported clipboard content type")),
+                                },
+                                None => Err(not_found("Unknown history entry")),
+                            },
+                            Request::ClipSend { text } => {
+                                let local_copy = LocalCopy::new(ClipboardContent::Text(text));
+                                if sync_pause.outgoing {
+                                    Err(failed("Outgoing sharing is paused"))
+                                } else if let Some(reason) = clipboard_filter.check(&local_copy) {
+                                    Err(failed(format!("Content is not shared: {reason}")))
+                                } else {
+                                    match clipboard.set_content(&local_copy.content) {
+                                        Ok(()) => {
+                                            // Watcher echo of the value is suppressed
+                                            clipboard_state.observe_local(&local_copy.content);
+                                            let update = ClipboardUpdate::new(&local_peer_id, clipboard_state.next_seq(), &local_copy.content);
+                                            history.push(&update);
+                                            share_update(&mut swarm, &peer_store, &events, &update);
+                                            Ok(Response::Ok)
+                                        }
+                                        Err(err) => Err(failed(err)),
                                     }
                                 }
-                                peers_online.extend(peers_list);
                             }
+                            Request::ClipGet => match clipboard.get_content() {
+                                Ok(Some(content)) => {
+                                    Ok(Response::Text(match content.text() {
+                                        Some(text) => text.to_string(),
+                                        None => format!("[{}, {} bytes]", content.mime(), content.as_bytes().len()),
+                                    }))
+                                }
+                                Ok(None) => Ok(Response::Text(String::new())),
+                                Err(err) => Err(failed(err)),
+                            },
+                            Request::Pause { direction } | Request::Resume { direction } => {
+                                sync_pause.set(direction, matches!(request, Request::Pause { .. }));
+                                save_sync_pause(&data_dir, &sync_pause).unwrap_or_else(|err| log::error!("Failed to save sync state: {err}"));
+                                Ok(Response::Ok)
+                            }
+                            Request::SyncStatus => Ok(Response::SyncStatus(sync_pause.status())),
+                            // Served by control socket, datagrams can not carry a stream
+                            Request::Subscribe => Err(ProtoError::new(ErrorCode::InvalidRequest, "Events can only be streamed over control socket")),
+                            Request::Peers => {
+                                Ok(Response::Peers(peer_store.records().iter().map(PeerRecord::info).collect()))
+                            }
+                            Request::SetPolicy { peer, policy } => {
+                                match peer_store.resolve(&peer) {
+                                    Ok(peer_id) if peer_store.is_trusted(&peer_id) => {
+                                        if let Some(record) = peer_store.get_mut(&peer_id) {
+                                            record.policy = policy;
+                                        }
+                                        save_peers(&peer_store);
+                                        Ok(Response::Ok)
+                                    }
+                                    Ok(_) => Err(unknown_peer("Unknown peer")),
+                                    Err(err) => Err(unknown_peer(err)),
+                                }
+                            }
+                    };
+                    // Client app may have given up waiting
+                    let _ = respond.send(result);
+            },
+            // Listen for swarm events
+            event = swarm.select_next_some() => match event {
+                SwarmEvent::NewListenAddr { address, .. } => log::info!("Listening on {address:?}"),
+                SwarmEvent::ListenerClosed { listener_id, reason, .. } => {
+                    if let Some(addr) = relay_listeners.remove(&listener_id) {
+                        log::info!("Lost slot on relay {addr}: {reason:?}");
+                    }
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Mdns(mdns::Event::Discovered(peers_list))) => {
+                    for (peer_id, addr) in peers_list.iter() {
+                        let _ = events.send(Event::PeerDiscovered {
+                            peer_id: peer_id.to_string(),
+                            name: peer_store.display_name(peer_id),
+                            addr: addr.to_string(),
+                        });
+                    }
+                    // Kept so trusted peers can be dialed when mdns does not reach them,
+                    // in private network too
+                    let mut new_addrs = false;
+                    for (peer_id, addr) in peers_list.iter() {
+                        swarm.behaviour_mut().kademlia.add_address(peer_id, addr.clone());
+                        new_addrs |= peer_store.add_addr(peer_id, addr);
+                    }
+                    if new_addrs {
+                        save_peers(&peer_store);
+                    }
+                    // In private network peers are listed once they complete a handshake
+                    if swarm_key.is_some() {
+                        let discovered: HashSet<PeerId> = peers_list.iter().map(|(peer_id, _)| *peer_id).collect();
+                        for peer_id in discovered {
+                            let opts = DialOpts::peer_id(peer_id).condition(PeerCondition::Disconnected).build();
+                            if let Err(err) = swarm.dial(opts) {
+                                log::debug!("Failed to dial {peer_id}: {err}");
+                            }
+                        }
+                        continue;
+                    }
+                    // Store active peers
+                    if !peers_list.is_empty(){
+                        peers_online_system.extend(peers_list.clone());
+                    }
+                    let peers_list = filter_incoming_peers(&peers_online, peers_list);
+                    if !peers_list.is_empty() {
+                        if peer_store.trusted().next().is_some() {
+                            let peer_id = PeerId::from_str(&peers_list.clone().into_iter().nth(0).unwrap().0)?;
+                            if peer_store.is_trusted(&peer_id) {
+                                swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+                            }
+                        }
+                        peers_online.extend(peers_list);
+                    }
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Mdns(mdns::Event::Expired(peers_list))) => {
+                    for (peer_id, addr) in peers_list.iter() {
+                        let _ = events.send(Event::PeerExpired {
+                            peer_id: peer_id.to_string(),
+                            name: peer_store.display_name(peer_id),
+                            addr: addr.to_string(),
+                        });
+                    }
+                    // Remove expired peers: it will be every 3 sec
+                    peers_online_system.retain(|chunk| !peers_list.contains(&chunk));
+                    let peers_list: Vec<(String, String)> = peers_list
+                        .into_iter()
+                        .map(|(peer_id, addr)| {
+                            (
+                                peer_id.to_string(),
+                                addr.to_string().split('/').nth(2).unwrap().to_string(),
+                            )
+                        })
+                        .collect();
+                    peers_online.retain(|chunk| !peers_list.contains(&chunk));
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Message { propagation_source, message_id, message })) => {
+                    // Only signed messages authored by trusted peers are accepted and forwarded
+                    let trusted = message.source.is_some_and(|source| peer_store.is_trusted(&source));
+                    let clipboard_topic = message.topic == update_topic.hash();
+                    let acceptance = match (trusted, clipboard_topic) {
+                        (false, _) => gossipsub::MessageAcceptance::Reject,
+                        (true, false) => gossipsub::MessageAcceptance::Ignore,
+                        (true, true) => gossipsub::MessageAcceptance::Accept,
+                    };
+                    if let Err(err) = swarm.behaviour_mut().gossipsub.report_message_validation_result(&message_id, &propagation_source, acceptance) {
+                        log::error!("Failed to report message validation result: {err}");
+                    }
+                    let Some(source) = message.source.filter(|_| trusted) else {
+                        log::info!("Rejected message from untrusted peer {:?}", message.source);
+                        continue;
+                    };
+                    if !clipboard_topic {
+                        continue;
+                    }
+                    if sync_pause.incoming {
+                        log::info!("Incoming sharing is paused, skipping clipboard update");
+                        continue;
+                    }
+                    if !peer_store.policy(&source).can_receive() {
+                        log::info!("Policy of {source} does not allow receiving, skipping clipboard update");
+                        continue;
+                    }
+                    match ClipboardUpdate::decode(&message.data, message.source) {
+                        Ok(update) if clipboard_state.accept_remote(&source, &update) => {
+                            if apply_update(&mut clipboard, &mut clipboard_state, &mut history, &update) {
+                                let _ = events.send(received_event(&update, &peer_store));
+                            }
+                        }
+                        Ok(_) => {}
+                        Err(err) => log::error!("Failed to decode clipboard update: {err}"),
+                    }
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::ClipboardTransfer(request_response::Event::Message { peer, message })) => match message {
+                    request_response::Message::Request { request: update, channel, .. } => {
+                        let verified = update.verify(&peer);
+                        if let Err(err) = &verified {
+                            log::error!("Invalid clipboard update from {peer}: {err}");
+                        }
+                        let accepted = verified.is_ok() && !sync_pause.incoming && peer_store.is_trusted(&peer) && peer_store.policy(&peer).can_receive();
+                        if swarm.behaviour_mut().clipboard_transfer.send_response(channel, PushResponse { accepted }).is_err() {
+                            log::debug!("Failed to answer clipboard update of {peer}");
+                        }
+                        if !accepted {
+                            log::info!("Skipping clipboard update from {peer}");
+                        } else if clipboard_state.accept_remote(&peer, &update)
+                            && apply_update(&mut clipboard, &mut clipboard_state, &mut history, &update)
+                        {
+                            let _ = events.send(received_event(&update, &peer_store));
+                        }
+                    }
+                    request_response::Message::Response { response, .. } => {
+                        if response.accepted {
+                            log::info!("Shared clipboard content with {peer}");
+                        } else {
+                            log::info!("{peer} does not take clipboard updates from this node");
                         }
-                        SwarmEvent::Behaviour(BehaviourEvent::Mdns(mdns::Event::Expired(peers_list))) => {
-                            // Remove expired peers: it will be every 3 sec
-                            peers_online_system.retain(|chunk| !peers_list.contains(&chunk));
-                            let peers_list: Vec<(String, String)> = peers_list
-                                .into_iter()
-                                .map(|(peer_id, addr)| {
-                                    (
-                                        peer_id.to_string(),
-                                        addr.to_string().split('/').nth(2).unwrap().to_string(),
-                                    )
-                                })
-                                .collect();
-                            peers_online.retain(|chunk| !peers_list.contains(&chunk));
+                    }
+                },
+                SwarmEvent::Behaviour(BehaviourEvent::ClipboardTransfer(request_response::Event::OutboundFailure { peer, error, .. })) => {
+                    log::error!("Failed to share clipboard content with {peer}: {error}");
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Pairing(request_response::Event::Message { peer, message })) => {
+                    match message {
+                        request_response::Message::Request { request, channel, .. } => {
+                            if matches!(request, PairRequest::Unpair) && peer_store.is_trusted(&peer) {
+                                log::info!("{peer} has removed this node");
+                                forget_peer(&mut swarm, &mut peer_store, peer);
+                                // Disconnected once answer is sent
+                                unpairing.insert(peer);
+                            }
+                            // Pairings with a redeemed token need no confirmation from user
+                            let started = matches!(request, PairRequest::Start { token: None, .. });
+                            let response = pairings.handle_request(peer, request);
+                            if started && matches!(response, PairResponse::Started { .. }) {
+                                let _ = events.send(Event::PairingRequested {
+                                    peer_id: peer.to_string(),
+                                    name: peer_store.display_name(&peer),
+                                });
+                            }
+                            if swarm.behaviour_mut().pairing.send_response(channel, response).is_err() {
+                                log::error!("Failed to answer pairing request of {peer}");
+                            }
                         }
-                        SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Message { propagation_source: _peer_id, message_id: _id, message })) => {
-                            let msg_str = String::from_utf8_lossy(&message.data);
-                            if message.topic.clone() == update_topic.hash() {
-                                clipboard.set_contents(msg_str.to_string())?;
+                        request_response::Message::Response { response, .. } => {
+                            if unpairing.remove(&peer) {
+                                let _ = swarm.disconnect_peer_id(peer);
+                                continue;
+                            }
+                            if let Some(request) = pairings.handle_response(peer, response) {
+                                swarm.behaviour_mut().pairing.send_request(&peer, request);
                             }
                         }
-                        _ => {}
-                    },
-                    // Listen for clipboard updates from pooler
-        /*             update = receiver.recv() => match update {
-                        Some(updated_clipboard) => {
-                            if swarm.behaviour_mut().gossipsub.all_peers().count() > 0 {
-                               swarm.behaviour_mut().gossipsub.publish(update_topic.clone(), updated_clipboard.as_bytes())?;
-                               log::info!("Shared clipboar content with peers");
+                    }
+                    for peer_id in pairings.take_completed() {
+                        trust_peer(&mut swarm, &mut peer_store, peer_id);
+                    }
+                }
+                SwarmEvent::ConnectionEstablished { peer_id, endpoint, num_established, .. } => {
+                    redials.connected(&peer_id);
+                    for addr in config.bootstrap.iter() {
+                        if is_bootstrap_addr(addr, &peer_id, endpoint.get_remote_address()) {
+                            bootstrap_redials.connected(addr);
+                            bootstrap_peers.insert(addr.clone(), peer_id);
+                        }
+                    }
+                    // Peers connected without mdns are listed as well
+                    if !peers_online.iter().any(|(peer, _)| *peer == peer_id.to_string()) {
+                        let addr = endpoint.get_remote_address().to_string();
+                        peers_online.push((peer_id.to_string(), addr.split('/').nth(2).unwrap_or_default().to_string()));
+                    }
+                    if peer_store.is_trusted(&peer_id) {
+                        // Only dialed addresses can be dialed again later
+                        peer_store.seen(&peer_id, endpoint.is_dialer().then(|| endpoint.get_remote_address()));
+                        save_peers(&peer_store);
+                    }
+                    if num_established.get() == 1 {
+                        swarm.behaviour_mut().device_info.send_request(&peer_id, device_info.clone());
+                        let _ = events.send(Event::ConnectionEstablished {
+                            peer_id: peer_id.to_string(),
+                            name: peer_store.display_name(&peer_id),
+                            addr: endpoint.get_remote_address().to_string(),
+                        });
+                    }
+                }
+                SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => {
+                    // Peers found by mdns are removed once they expire
+                    if !peers_online_system.iter().any(|(peer, _)| *peer == peer_id) {
+                        peers_online.retain(|(peer, _)| *peer != peer_id.to_string());
+                    }
+                    if peer_store.is_trusted(&peer_id) {
+                        peer_store.seen(&peer_id, None);
+                        save_peers(&peer_store);
+                    }
+                    let _ = events.send(Event::ConnectionClosed {
+                        peer_id: peer_id.to_string(),
+                        name: peer_store.display_name(&peer_id),
+                    });
+                }
+                SwarmEvent::OutgoingConnectionError { peer_id: Some(peer_id), error, .. } => {
+                    log::debug!("Failed to connect to {peer_id}: {error}");
+                    // Peer may have moved, ask kademlia where it is now
+                    if peer_store.is_trusted(&peer_id) && !swarm.is_connected(&peer_id) && lookups.insert(peer_id) {
+                        swarm.behaviour_mut().kademlia.get_closest_peers(peer_id);
+                    }
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Kademlia(kad::Event::RoutingUpdated { peer, addresses, .. })) => {
+                    let mut new_addrs = false;
+                    for addr in addresses.iter() {
+                        new_addrs |= peer_store.add_addr(&peer, addr);
+                    }
+                    if new_addrs {
+                        save_peers(&peer_store);
+                    }
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed { result: kad::QueryResult::GetClosestPeers(result), step, .. })) if step.last => {
+                    let (key, peers) = match result {
+                        Ok(kad::GetClosestPeersOk { key, peers }) => (key, peers),
+                        Err(kad::GetClosestPeersError::Timeout { key, peers }) => (key, peers),
+                    };
+                    let Ok(peer_id) = PeerId::from_bytes(&key) else {
+                        continue;
+                    };
+                    lookups.remove(&peer_id);
+                    if peers.contains(&peer_id) && !swarm.is_connected(&peer_id) {
+                        log::info!("Found {peer_id} in kademlia, dialing it");
+                        let opts = DialOpts::peer_id(peer_id).condition(PeerCondition::Disconnected).build();
+                        if let Err(err) = swarm.dial(opts) {
+                            log::debug!("Failed to dial {peer_id}: {err}");
+                        }
+                    }
+                }
+                // Addresses other resk nodes listen on, including relayed ones
+                SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Received { peer_id, info })) if info.protocols.contains(&KAD_PROTOCOL) => {
+                    for addr in info.listen_addrs {
+                        swarm.behaviour_mut().kademlia.add_address(&peer_id, addr);
+                    }
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::RelayClient(event)) => match event {
+                    relay::client::Event::ReservationReqAccepted { relay_peer_id, renewal: false, .. } => {
+                        log::info!("Reachable through relay {relay_peer_id}");
+                        for addr in config.relay.addrs.iter() {
+                            if addr_peer_id(addr) == Some(relay_peer_id) {
+                                relay_redials.connected(addr);
                             }
                         }
-                        _ => {}
-                    } */
+                    }
+                    relay::client::Event::ReservationReqFailed { relay_peer_id, error, .. } => {
+                        log::error!("Failed to reserve slot on relay {relay_peer_id}: {error}");
+                    }
+                    event => log::debug!("{event:?}"),
+                },
+                SwarmEvent::Behaviour(BehaviourEvent::Dcutr(event)) => match event {
+                    dcutr::Event::DirectConnectionUpgradeSucceeded { remote_peer_id } => {
+                        log::info!("Connected directly to {remote_peer_id}");
+                    }
+                    dcutr::Event::DirectConnectionUpgradeFailed { remote_peer_id, error } => {
+                        log::info!("Staying on relayed connection to {remote_peer_id}: {error}");
+                    }
+                    event => log::debug!("{event:?}"),
+                },
+                SwarmEvent::Behaviour(BehaviourEvent::DeviceInfo(request_response::Event::Message { peer, message })) => {
+                    let info = match message {
+                        request_response::Message::Request { request, channel, .. } => {
+                            let _ = swarm.behaviour_mut().device_info.send_response(channel, device_info.clone());
+                            request
+                        }
+                        request_response::Message::Response { response, .. } => response,
+                    };
+                    log::debug!("{peer} is named {}", info.name);
+                    if peer_store.set_name(peer, info.name) {
+                        save_peers(&peer_store);
+                    }
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Pairing(request_response::Event::OutboundFailure { peer, error, .. })) => {
+                    if unpairing.remove(&peer) {
+                        let _ = swarm.disconnect_peer_id(peer);
+                    }
+                    pairings.fail(&peer, &error.to_string());
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Pairing(
+                    request_response::Event::ResponseSent { peer, .. } | request_response::Event::InboundFailure { peer, .. },
+                )) if unpairing.remove(&peer) => {
+                    let _ = swarm.disconnect_peer_id(peer);
+                }
+                _ => {}
+            },
+            // Listen for clipboard updates from watcher
+            update = receiver.recv() => if let Some(local_copy) = update {
+                // Only genuine local edits are recorded and shared
+                if !clipboard_state.observe_local(&local_copy.content) {
+                    continue;
+                }
+                if let Some(reason) = clipboard_filter.check(&local_copy) {
+                    log::info!("Skipping clipboard content: {reason}");
+                    continue;
+                }
+                // Copies made while sharing is paused, e.g. passwords, are not recorded either
+                if sync_pause.outgoing {
+                    log::info!("Outgoing sharing is paused, skipping clipboard content");
+                    continue;
+                }
+                let update = ClipboardUpdate::new(&local_peer_id, clipboard_state.next_seq(), &local_copy.content);
+                history.push(&update);
+                share_update(&mut swarm, &peer_store, &events, &update);
+            },
+            // Keep kademlia routing table fresh
+            _ = bootstrap_timer.tick() => {
+                if let Err(err) = swarm.behaviour_mut().kademlia.bootstrap() {
+                    log::debug!("Skipping kademlia bootstrap: {err}");
+                }
+            }
+            // Reconnect to trusted peers on their last known addresses
+            _ = redial_timer.tick() => {
+                for addr in config.relay.addrs.iter() {
+                    if relay_listeners.values().any(|listening| listening == addr) || !relay_redials.attempt(addr.clone()) {
+                        continue;
+                    }
+                    match swarm.listen_on(addr.clone().with(Protocol::P2pCircuit)) {
+                        Ok(listener_id) => {
+                            relay_listeners.insert(listener_id, addr.clone());
+                        }
+                        Err(err) => log::error!("Failed to listen on relay {addr}: {err}"),
+                    }
                 }
+                for addr in config.bootstrap.iter() {
+                    let peer_id = bootstrap_peers.get(addr).copied().or_else(|| addr_peer_id(addr));
+                    if peer_id.is_some_and(|peer_id| swarm.is_connected(&peer_id)) || !bootstrap_redials.attempt(addr.clone()) {
+                        continue;
+                    }
+                    log::debug!("Dialing bootstrap address {addr}");
+                    if let Err(err) = swarm.dial(addr.clone()) {
+                        log::debug!("Failed to dial bootstrap address {addr}: {err}");
+                    }
+                }
+                let offline: Vec<(PeerId, Vec<Multiaddr>)> = peer_store
+                    .offline(|peer_id| swarm.is_connected(peer_id))
+                    .filter(|record| redials.attempt(record.peer_id))
+                    .map(|record| (record.peer_id, record.addrs.clone()))
+                    .collect();
+                for (peer_id, mut addrs) in offline {
+                    // Relays are tried after direct addresses
+                    addrs.extend(config.relay.addrs.iter().map(|relay| {
+                        relay.clone().with(Protocol::P2pCircuit).with(Protocol::P2p(peer_id))
+                    }));
+                    log::debug!("Dialing offline peer {peer_id}");
+                    let opts = DialOpts::peer_id(peer_id)
+                        .addresses(addrs)
+                        .condition(PeerCondition::Disconnected)
+                        .build();
+                    if let Err(err) = swarm.dial(opts) {
+                        log::debug!("Failed to dial {peer_id}: {err}");
+                    }
+                }
+            }
+        }
     }
 }
 
-async fn start_pooling_clipboard(
-    sender: Sender<String>,
-    flutter_udp_port: Option<i32>,
-) {
-    // Init clipboard
-    desktop! {
-        let mut clipboard: ClipboardContext =
-            ClipboardProvider::new().expect("Failed to init clipboard")
-    };
-    mobile! {
-        let mut clipboard: MobileClipboard =
-            MobileClipboard::new(flutter_udp_port.clone().unwrap())
-                .expect("Failed to init clipboard")
+// Listen address other devices on local network can dial
+fn shareable_addr(swarm: &Swarm<Behaviour>) -> Option<Multiaddr> {
+    swarm
+        .listeners()
+        .find(|addr| {
+            let mut protocols = addr.iter();
+            matches!(protocols.next(), Some(Protocol::Ip4(ip)) if !ip.is_loopback())
+                && matches!(protocols.next(), Some(Protocol::Tcp(_)))
+        })
+        .cloned()
+}
 
+// Relay server only takes reservations and circuits of trusted peers,
+// so it is not an open relay for anyone who finds it
+// Default limits of 128 KiB and 2 minutes per circuit are meant for
+// strangers, they would cut off screenshots and drop relayed peers every
+// 2 minutes. Circuits only carry trusted peers here, so they get room for
+// many full transfers and last for hours, dcutr usually replaces them
+// with direct connections long before that anyway
+fn relay_config(trusted_peers: TrustedPeers) -> relay::Config {
+    let mut relay_config = relay::Config {
+        max_circuit_bytes: 16 * MAX_TRANSFER_SIZE as u64,
+        max_circuit_duration: Duration::from_secs(12 * 60 * 60),
+        ..relay::Config::default()
     };
+    let reserving = trusted_peers.clone();
+    relay_config.reservation_rate_limiters.push(Box::new(
+        move |peer_id, _: &Multiaddr, _| reserving.contains(&peer_id),
+    ));
+    relay_config.circuit_src_rate_limiters.push(Box::new(
+        move |peer_id, _: &Multiaddr, _| trusted_peers.contains(&peer_id),
+    ));
+    relay_config
+}
 
-    // Get initial clipboard content
-    let mut last_clipboard_content = clipboard
-        .get_contents()
-        .expect("Failed to get clipboard content");
+// Record peer as trusted after pairing
+fn trust_peer(
+    swarm: &mut Swarm<Behaviour>,
+    peer_store: &mut PeerStore,
+    peer_id: PeerId,
+) {
+    log::info!("Paired with {peer_id}");
+    swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+    if peer_store.add(peer_id) {
+        save_peers(peer_store);
+    }
+}
 
-    // Check if it was changed
-    loop {
-        sleep(Duration::from_secs(1)).await;
-        let current_clipboard_content = clipboard
-            .get_contents()
-            .expect("Failed to get clipboard content");
-        if current_clipboard_content != last_clipboard_content {
-            last_clipboard_content = current_clipboard_content.clone();
-            sender
-                .send(last_clipboard_content.clone())
-                .await
-                .expect("Failed to send value");
+// Drop trusted peer, so nothing is shared with it anymore
+fn forget_peer(
+    swarm: &mut Swarm<Behaviour>,
+    peer_store: &mut PeerStore,
+    peer_id: PeerId,
+) {
+    log::info!("Removing peer {peer_id}");
+    swarm
+        .behaviour_mut()
+        .gossipsub
+        .remove_explicit_peer(&peer_id);
+    if peer_store.remove(&peer_id).is_some() {
+        save_peers(peer_store);
+    }
+}
+
+fn save_peers(peer_store: &PeerStore) {
+    peer_store
+        .save()
+        .unwrap_or_else(|err| log::error!("Failed to save peers: {err}"));
+}
+
+// Errors of control requests, message is shown to user as is
+fn failed(err: impl ToString) -> ProtoError {
+    ProtoError::new(ErrorCode::Failed, err.to_string())
+}
+
+fn invalid_argument(err: impl ToString) -> ProtoError {
+    ProtoError::new(ErrorCode::InvalidArgument, err.to_string())
+}
+
+fn unknown_peer(err: impl ToString) -> ProtoError {
+    ProtoError::new(ErrorCode::UnknownPeer, err.to_string())
+}
+
+fn not_found(err: impl ToString) -> ProtoError {
+    ProtoError::new(ErrorCode::NotFound, err.to_string())
+}
+
+// Push local update over authenticated streams,
+// so only connected peers policies allow get it
+fn share_update(
+    swarm: &mut Swarm<Behaviour>,
+    peer_store: &PeerStore,
+    events: &broadcast::Sender<Event>,
+    update: &ClipboardUpdate,
+) {
+    if update.size() > MAX_TRANSFER_SIZE {
+        log::info!("Clipboard content is too big to be shared");
+        return;
+    }
+    let recipients: Vec<PeerId> = peer_store
+        .records()
+        .iter()
+        .filter(|record| {
+            record.policy.can_send() && swarm.is_connected(&record.peer_id)
+        })
+        .map(|record| record.peer_id)
+        .collect();
+    for peer_id in recipients.iter() {
+        swarm
+            .behaviour_mut()
+            .clipboard_transfer
+            .send_request(peer_id, update.clone());
+    }
+    if !recipients.is_empty() {
+        let peers = recipients.iter().map(PeerId::to_string).collect();
+        let _ = events.send(Event::ClipboardSent {
+            mime: update.mime.clone(),
+            size: update.size(),
+            peers,
+        });
+    }
+}
+
+// Put content of update received from a peer on the clipboard
+fn apply_update(
+    clipboard: &mut PlatformClipboard,
+    clipboard_state: &mut ClipboardState,
+    history: &mut ClipboardHistory,
+    update: &ClipboardUpdate,
+) -> bool {
+    let Some(content) = update.content() else {
+        log::info!("Unsupported clipboard content type {}", update.mime);
+        return false;
+    };
+    match clipboard.set_content(&content) {
+        Ok(()) => clipboard_state.set_remote(&content),
+        Err(err) => {
+            log::error!("Failed to set clipboard content: {err}");
+            return false;
         }
     }
+    history.push(update);
+    true
 }
 
+fn received_event(update: &ClipboardUpdate, peer_store: &PeerStore) -> Event {
+    Event::ClipboardReceived {
+        origin: update.origin.clone(),
+        origin_name: PeerId::from_str(&update.origin)
+            .ok()
+            .and_then(|peer_id| peer_store.display_name(&peer_id)),
+        mime: update.mime.clone(),
+        size: update.size(),
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+type PlatformClipboard = DesktopClipboard;
+#[cfg(any(target_os = "android", target_os = "ios"))]
+type PlatformClipboard = MobileClipboard;
+
 #[derive(NetworkBehaviour)]
 struct Behaviour {
     mdns: mdns::tokio::Behaviour,
     gossipsub: gossipsub::Behaviour,
     kademlia: Kademlia<MemoryStore>,
+    clipboard_transfer: request_response::Behaviour<TransferCodec>,
+    pairing: request_response::json::Behaviour<PairRequest, PairResponse>,
+    device_info: request_response::json::Behaviour<DeviceInfo, DeviceInfo>,
+    identify: identify::Behaviour,
+    relay_client: relay::client::Behaviour,
+    relay_server: Toggle<relay::Behaviour>,
+    dcutr: dcutr::Behaviour,
 }
 
 async fn build_transport(
     local_key: &Keypair,
+    relay_transport: relay::client::Transport,
+    swarm_key: Option<PreSharedKey>,
 ) -> Result<transport::Boxed<(PeerId, StreamMuxerBox)>, Box<dyn Error>> {
-    let tcp_transport =
-        tcp::tokio::Transport::new(tcp::Config::default().nodelay(true))
-            .upgrade(upgrade::Version::V1Lazy)
-            .authenticate(noise::Config::new(local_key)?)
-            .multiplex(yamux::Config::default())
-            .timeout(Duration::from_secs(20))
-            .boxed();
+    // Relayed connections are upgraded the same way as tcp ones
+    let base_transport = OrTransport::new(
+        relay_transport,
+        tcp::tokio::Transport::new(tcp::Config::default().nodelay(true)),
+    );
+    let base_transport =
+        match swarm_key {
+            Some(key) => either::Either::Left(base_transport.and_then(
+                move |socket, _| PnetConfig::new(key).handshake(socket),
+            )),
+            None => either::Either::Right(base_transport),
+        };
+    let tcp_transport = base_transport
+        .upgrade(upgrade::Version::V1Lazy)
+        .authenticate(noise::Config::new(local_key)?)
+        .multiplex(yamux::Config::default())
+        .timeout(Duration::from_secs(20))
+        .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)))
+        .boxed();
+    if swarm_key.is_some() {
+        return Ok(tcp_transport);
+    }
     let quic_transport =
         quic::tokio::Transport::new(quic::Config::new(local_key));
 
@@ -343,9 +1185,7 @@ async fn build_transport(
             Either::Left((peer_id, muxer)) => {
                 (peer_id, StreamMuxerBox::new(muxer))
             }
-            Either::Right((peer_id, muxer)) => {
-                (peer_id, StreamMuxerBox::new(muxer))
-            }
+            Either::Right(output) => output,
         })
         .boxed();
     Ok(transport)
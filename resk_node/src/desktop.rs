@@ -0,0 +1,76 @@
+// Desktop specific code
+use arboard::{Clipboard, ImageData};
+use image::{ImageFormat, RgbaImage};
+use std::borrow::Cow;
+use std::error::Error;
+use std::io::Cursor;
+
+use crate::clipboard_sync::ClipboardContent;
+
+pub struct DesktopClipboard {
+    clipboard: Clipboard,
+}
+
+impl DesktopClipboard {
+    pub fn new() -> Result<Self, Box<dyn Error>> {
+        Ok(DesktopClipboard {
+            clipboard: Clipboard::new()?,
+        })
+    }
+    // Text if clipboard has any, together with html if it is available
+    // Otherwise image encoded as PNG
+    pub fn get_content(
+        &mut self,
+    ) -> Result<Option<ClipboardContent>, Box<dyn Error>> {
+        if let Ok(text) = self.clipboard.get_text() {
+            return Ok(Some(match self.clipboard.get().html() {
+                Ok(html) => ClipboardContent::Html { html, text },
+                Err(_) => ClipboardContent::Text(text),
+            }));
+        }
+        match self.clipboard.get_image() {
+            Ok(image) => Ok(Some(ClipboardContent::Image(encode_png(image)?))),
+            Err(arboard::Error::ContentNotAvailable) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+    pub fn set_content(
+        &mut self,
+        content: &ClipboardContent,
+    ) -> Result<(), Box<dyn Error>> {
+        match content {
+            ClipboardContent::Text(text) => {
+                self.clipboard.set_text(text.as_str())?
+            }
+            ClipboardContent::Html { html, text } => self
+                .clipboard
+                .set_html(html.as_str(), Some(text.as_str()))?,
+            ClipboardContent::Image(png) => {
+                self.clipboard.set_image(decode_png(png)?)?
+            }
+        }
+        Ok(())
+    }
+}
+
+fn encode_png(image: ImageData) -> Result<Vec<u8>, Box<dyn Error>> {
+    let image = RgbaImage::from_raw(
+        image.width as u32,
+        image.height as u32,
+        image.bytes.into_owned(),
+    )
+    .ok_or("Clipboard image has invalid size")?;
+    let mut png = Cursor::new(Vec::new());
+    image.write_to(&mut png, ImageFormat::Png)?;
+    Ok(png.into_inner())
+}
+
+fn decode_png(png: &[u8]) -> Result<ImageData<'static>, Box<dyn Error>> {
+    let image =
+        image::load_from_memory_with_format(png, ImageFormat::Png)?.to_rgba8();
+    Ok(ImageData {
+        width: image.width() as usize,
+        height: image.height() as usize,
+        bytes: Cow::Owned(image.into_raw()),
+    })
+}
@@ -0,0 +1,172 @@
+// Reconnecting to trusted peers that are offline
+// Peers are dialed on their last known addresses, so they are found even
+// where mdns does not work. Every failed attempt doubles the wait before
+// the next one. Peers not reachable there are looked up in kademlia
+// Static bootstrap addresses are redialed the same way
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId, StreamProtocol};
+use std::collections::HashMap;
+use std::hash::Hash;
+use tokio::time::{Duration, Instant};
+
+// Own protocol keeps resk nodes out of other kademlia networks
+pub const KAD_PROTOCOL: StreamProtocol = StreamProtocol::new("/resk/kad/1");
+// How often kademlia routing table is refreshed
+pub const BOOTSTRAP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+// How often offline peers are checked
+pub const REDIAL_INTERVAL: Duration = Duration::from_secs(15);
+const MIN_BACKOFF: Duration = Duration::from_secs(15);
+const MAX_BACKOFF: Duration = Duration::from_secs(30 * 60);
+
+struct Backoff {
+    attempts: u32,
+    next_attempt: Instant,
+}
+
+// Keyed by peer id, or by address for bootstrap peers
+pub struct Redials<K> {
+    backoffs: HashMap<K, Backoff>,
+}
+
+impl<K: Eq + Hash> Default for Redials<K> {
+    fn default() -> Self {
+        Redials {
+            backoffs: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Eq + Hash> Redials<K> {
+    pub fn new() -> Self {
+        Redials::default()
+    }
+
+    // Whether peer should be dialed now, schedules next attempt if so
+    pub fn attempt(&mut self, key: K) -> bool {
+        let now = Instant::now();
+        let backoff = self.backoffs.entry(key).or_insert(Backoff {
+            attempts: 0,
+            next_attempt: now,
+        });
+        if backoff.next_attempt > now {
+            return false;
+        }
+        let delay = MIN_BACKOFF
+            .saturating_mul(2u32.saturating_pow(backoff.attempts))
+            .min(MAX_BACKOFF);
+        backoff.attempts = backoff.attempts.saturating_add(1);
+        backoff.next_attempt = now + delay;
+        true
+    }
+
+    // Peer is reachable again, next time it goes offline start over
+    pub fn connected(&mut self, key: &K) {
+        self.backoffs.remove(key);
+    }
+}
+
+// Peer id address ends with, if any
+pub fn addr_peer_id(addr: &Multiaddr) -> Option<PeerId> {
+    match addr.iter().last() {
+        Some(Protocol::P2p(peer_id)) => Some(peer_id),
+        _ => None,
+    }
+}
+
+// Whether connection on remote address was made to bootstrap address
+pub fn is_bootstrap_addr(
+    bootstrap: &Multiaddr,
+    peer_id: &PeerId,
+    remote: &Multiaddr,
+) -> bool {
+    match addr_peer_id(bootstrap) {
+        Some(bootstrap_peer) => bootstrap_peer == *peer_id,
+        None => without_peer_id(remote) == *bootstrap,
+    }
+}
+
+fn without_peer_id(addr: &Multiaddr) -> Multiaddr {
+    let mut addr = addr.clone();
+    if addr_peer_id(&addr).is_some() {
+        addr.pop();
+    }
+    addr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::advance;
+
+    #[tokio::test(start_paused = true)]
+    async fn doubles_backoff_after_every_attempt() {
+        let mut redials = Redials::new();
+        let peer_id = PeerId::random();
+        assert!(redials.attempt(peer_id));
+        assert!(!redials.attempt(peer_id));
+
+        advance(MIN_BACKOFF).await;
+        assert!(redials.attempt(peer_id));
+
+        advance(MIN_BACKOFF).await;
+        assert!(!redials.attempt(peer_id));
+        advance(MIN_BACKOFF).await;
+        assert!(redials.attempt(peer_id));
+
+        advance(MIN_BACKOFF * 3).await;
+        assert!(!redials.attempt(peer_id));
+        advance(MIN_BACKOFF).await;
+        assert!(redials.attempt(peer_id));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn caps_backoff() {
+        let mut redials = Redials::new();
+        let peer_id = PeerId::random();
+        for _ in 0..40 {
+            assert!(redials.attempt(peer_id));
+            advance(MAX_BACKOFF).await;
+        }
+        assert!(redials.attempt(peer_id));
+        advance(MAX_BACKOFF - Duration::from_secs(1)).await;
+        assert!(!redials.attempt(peer_id));
+        advance(Duration::from_secs(1)).await;
+        assert!(redials.attempt(peer_id));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connected_peer_starts_over() {
+        let mut redials = Redials::new();
+        let (peer_id, other) = (PeerId::random(), PeerId::random());
+        for _ in 0..5 {
+            assert!(redials.attempt(peer_id));
+            advance(MAX_BACKOFF).await;
+        }
+        assert!(redials.attempt(other));
+
+        redials.connected(&peer_id);
+        assert!(redials.attempt(peer_id));
+        // Other peers keep their backoff
+        assert!(!redials.attempt(other));
+        advance(MIN_BACKOFF).await;
+        assert!(redials.attempt(peer_id));
+    }
+
+    #[test]
+    fn matches_bootstrap_addresses() {
+        let peer_id = PeerId::random();
+        let addr: Multiaddr = "/ip4/10.0.0.1/tcp/4001".parse().unwrap();
+        let with_peer = addr.clone().with(Protocol::P2p(peer_id));
+        assert_eq!(addr_peer_id(&with_peer), Some(peer_id));
+        assert_eq!(addr_peer_id(&addr), None);
+
+        // Peer id decides when bootstrap address has one
+        let other: Multiaddr = "/ip4/10.0.0.2/tcp/4001".parse().unwrap();
+        assert!(is_bootstrap_addr(&with_peer, &peer_id, &other));
+        assert!(!is_bootstrap_addr(&with_peer, &PeerId::random(), &addr));
+
+        // Otherwise remote address has to match
+        assert!(is_bootstrap_addr(&addr, &peer_id, &with_peer));
+        assert!(is_bootstrap_addr(&addr, &peer_id, &addr));
+        assert!(!is_bootstrap_addr(&addr, &peer_id, &other));
+    }
+}
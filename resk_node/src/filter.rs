@@ -0,0 +1,58 @@
+// Checks of local copies before they leave this device
+use regex::Regex;
+use std::collections::HashMap;
+use std::error::Error;
+
+use crate::config::FilterConfig;
+use crate::watcher::LocalCopy;
+
+// MIME types used by password managers to mark secrets
+// together with the value that marks content as sensitive
+const SENSITIVE_HINTS: [(&str, &str); 2] = [
+    ("x-kde-passwordManagerHint", "secret"),
+    ("CLIPBOARD_STATE", "sensitive"),
+];
+
+// MIME types watchers have to read together with content
+pub fn is_hint(mime_type: &str) -> bool {
+    SENSITIVE_HINTS.iter().any(|(hint, _)| *hint == mime_type)
+}
+
+pub struct ClipboardFilter {
+    deny: Vec<Regex>,
+}
+
+impl ClipboardFilter {
+    pub fn new(config: &FilterConfig) -> Result<Self, Box<dyn Error>> {
+        let deny = config
+            .deny
+            .iter()
+            .map(|pattern| {
+                Regex::new(pattern).map_err(|err| {
+                    format!("Invalid filter rule {pattern:?}: {err}")
+                })
+            })
+            .collect::<Result<Vec<Regex>, String>>()?;
+        Ok(ClipboardFilter { deny })
+    }
+
+    // Reason why copy must not be shared, None if it can be
+    pub fn check(&self, copy: &LocalCopy) -> Option<String> {
+        if is_sensitive(&copy.hints) {
+            return Some("marked as sensitive by its source".to_string());
+        }
+        let text = copy.content.text()?;
+        self.deny
+            .iter()
+            .find(|rule| rule.is_match(text))
+            .map(|rule| format!("matches deny rule {:?}", rule.as_str()))
+    }
+}
+
+fn is_sensitive(hints: &HashMap<String, String>) -> bool {
+    SENSITIVE_HINTS.iter().any(|(hint, value)| {
+        hints.get(*hint).is_some_and(|offered| {
+            offered.trim_matches(['\0', ' ', '\n']) == *value
+        })
+    })
+}
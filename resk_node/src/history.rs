@@ -0,0 +1,315 @@
+// Persistent history of clipboard values
+use resk_proto::HistorySummary;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use tokio::sync::watch;
+use tokio::task;
+
+use crate::config::HistoryConfig;
+use crate::payload::ClipboardUpdate;
+
+const PREVIEW_LENGTH: usize = 60;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoryEntry {
+    pub id: u64,
+    pub update: ClipboardUpdate,
+}
+
+impl HistoryEntry {
+    // Text of entry, None for binary content
+    pub fn text(&self) -> Option<String> {
+        if !self.update.mime.starts_with("text/") {
+            return None;
+        }
+        Some(String::from_utf8_lossy(&self.update.payload).to_string())
+    }
+
+    pub fn summary(&self) -> HistorySummary {
+        let preview = match self.text() {
+            Some(text) => {
+                let line = text.lines().next().unwrap_or_default().trim();
+                let mut preview: String =
+                    line.chars().take(PREVIEW_LENGTH).collect();
+                if preview.len() < text.trim().len() {
+                    preview.push_str("...");
+                }
+                preview
+            }
+            None => format!(
+                "[{}, {} bytes]",
+                self.update.mime,
+                self.update.payload.len()
+            ),
+        };
+        HistorySummary {
+            id: self.id,
+            origin: self.update.origin.clone(),
+            origin_name: None,
+            timestamp: self.update.timestamp,
+            mime: self.update.mime.clone(),
+            preview,
+        }
+    }
+}
+
+pub struct ClipboardHistory {
+    path: PathBuf,
+    config: HistoryConfig,
+    // Oldest entries first
+    entries: Vec<HistoryEntry>,
+    // Latest entries for writer, older ones are skipped if it falls behind
+    saves: watch::Sender<Vec<HistoryEntry>>,
+}
+
+impl ClipboardHistory {
+    // Unreadable file is moved aside, so node starts with empty history
+    pub fn load(path: PathBuf, config: HistoryConfig) -> Self {
+        let entries = read_entries(&path).unwrap_or_else(|err| {
+            log::error!("Failed to load clipboard history: {err}");
+            let aside = path.with_extension("json.corrupt");
+            match fs::rename(&path, &aside) {
+                Ok(()) => log::info!("Moved it to {}", aside.display()),
+                Err(err) => log::error!("Failed to move it aside: {err}"),
+            }
+            vec![]
+        });
+        let (saves, _) = watch::channel(vec![]);
+        ClipboardHistory {
+            path,
+            config,
+            entries,
+            saves,
+        }
+    }
+
+    // Write history.json whenever entries change, away from event loop
+    pub fn spawn_writer(&self) {
+        let mut saves = self.saves.subscribe();
+        let path = self.path.clone();
+        tokio::spawn(async move {
+            while saves.changed().await.is_ok() {
+                let entries = saves.borrow_and_update().clone();
+                let path = path.clone();
+                let saved = task::spawn_blocking(move || {
+                    save(&path, &entries).map_err(|err| err.to_string())
+                })
+                .await
+                .unwrap_or_else(|err| Err(err.to_string()));
+                if let Err(err) = saved {
+                    log::error!("Failed to save clipboard history: {err}");
+                }
+            }
+        });
+    }
+
+    // Record update with full payload
+    pub fn push(&mut self, update: &ClipboardUpdate) {
+        if self.config.limit == 0 || update.size() > self.config.max_size {
+            return;
+        }
+        // Copying the same value again only moves it up
+        self.entries
+            .retain(|entry| entry.update.hash != update.hash);
+        let id = self.entries.iter().map(|entry| entry.id).max().unwrap_or(0);
+        self.entries.push(HistoryEntry {
+            id: id + 1,
+            update: update.clone(),
+        });
+
+        // Drop oldest entries to fit limits
+        let mut size: usize =
+            self.entries.iter().map(|entry| entry.update.size()).sum();
+        while self.entries.len() > self.config.limit
+            || size > self.config.max_size
+        {
+            let entry = self.entries.remove(0);
+            size -= entry.update.size();
+        }
+        self.saves.send_replace(self.entries.clone());
+    }
+
+    // Newest entries first
+    pub fn list(&self) -> Vec<HistorySummary> {
+        self.entries
+            .iter()
+            .rev()
+            .map(|entry| entry.summary())
+            .collect()
+    }
+
+    // Case insensitive search through text entries
+    pub fn search(&self, query: &str) -> Vec<HistorySummary> {
+        let query = query.to_lowercase();
+        self.entries
+            .iter()
+            .rev()
+            .filter(|entry| {
+                entry
+                    .text()
+                    .is_some_and(|text| text.to_lowercase().contains(&query))
+            })
+            .map(|entry| entry.summary())
+            .collect()
+    }
+
+    pub fn get(&self, id: u64) -> Option<&HistoryEntry> {
+        self.entries.iter().find(|entry| entry.id == id)
+    }
+}
+
+fn read_entries(path: &Path) -> Result<Vec<HistoryEntry>, Box<dyn Error>> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+}
+
+// Entries hold full clipboard values, only current user may read them
+// Written to a temporary file first, so a crash can not leave half of it
+fn save(path: &Path, entries: &[HistoryEntry]) -> Result<(), Box<dyn Error>> {
+    let tmp_path = path.with_extension("json.tmp");
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(&tmp_path)?;
+    // Mode is only applied to new files, leftovers are fixed here
+    #[cfg(unix)]
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(serde_json::to_string(entries)?.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clipboard_sync::ClipboardContent;
+    use libp2p::PeerId;
+    use tokio::time::{sleep, Duration};
+
+    // Fresh directory with history.json path in it
+    fn history_path() -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("resk-history-{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("history.json")
+    }
+
+    fn history(path: &Path, limit: usize, max_size: usize) -> ClipboardHistory {
+        ClipboardHistory::load(
+            path.to_path_buf(),
+            HistoryConfig { limit, max_size },
+        )
+    }
+
+    fn update(text: &str) -> ClipboardUpdate {
+        ClipboardUpdate::new(
+            &PeerId::random(),
+            1,
+            &ClipboardContent::Text(text.to_string()),
+        )
+    }
+
+    fn previews(history: &ClipboardHistory) -> Vec<String> {
+        history
+            .list()
+            .into_iter()
+            .map(|entry| entry.preview)
+            .collect()
+    }
+
+    #[test]
+    fn keeps_newest_entries_within_limit() {
+        let path = history_path();
+        let mut history = history(&path, 2, 1024);
+        for text in ["one", "two", "three"] {
+            history.push(&update(text));
+        }
+        assert_eq!(previews(&history), vec!["three", "two"]);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn keeps_entries_within_size() {
+        let path = history_path();
+        let mut history = history(&path, 10, 8);
+        history.push(&update("1234"));
+        history.push(&update("5678"));
+        history.push(&update("90"));
+        assert_eq!(previews(&history), vec!["90", "5678"]);
+        // Bigger than whole history, never recorded
+        history.push(&update("123456789"));
+        assert_eq!(previews(&history), vec!["90", "5678"]);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn same_value_moves_up() {
+        let path = history_path();
+        let mut history = history(&path, 10, 1024);
+        history.push(&update("one"));
+        history.push(&update("two"));
+        history.push(&update("one"));
+        assert_eq!(previews(&history), vec!["one", "two"]);
+        let ids: Vec<u64> =
+            history.list().into_iter().map(|entry| entry.id).collect();
+        assert_eq!(ids, vec![3, 2]);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn saved_history_loads_back() {
+        let path = history_path();
+        let mut history = history(&path, 10, 1024);
+        history.push(&update("one"));
+        history.push(&update("two"));
+        save(&path, &history.entries).unwrap();
+        assert!(!path.with_extension("json.tmp").exists());
+        #[cfg(unix)]
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        let loaded =
+            ClipboardHistory::load(path.clone(), HistoryConfig::default());
+        assert_eq!(previews(&loaded), vec!["two", "one"]);
+        assert_eq!(loaded.get(1).unwrap().text().as_deref(), Some("one"));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn corrupt_history_is_moved_aside() {
+        let path = history_path();
+        fs::write(&path, "[{\"id\": 1, \"upd").unwrap();
+        let history = history(&path, 10, 1024);
+        assert!(history.list().is_empty());
+        assert!(!path.exists());
+        assert!(path.with_extension("json.corrupt").exists());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn writer_saves_changes() {
+        let path = history_path();
+        let mut history = history(&path, 10, 1024);
+        history.spawn_writer();
+        history.push(&update("one"));
+        for _ in 0..100 {
+            if read_entries(&path).is_ok_and(|entries| entries.len() == 1) {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(read_entries(&path).unwrap().len(), 1);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
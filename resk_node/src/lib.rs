@@ -1,6 +1,19 @@
 // File to export code to other packages
 
+pub mod clipboard_sync;
+pub mod config;
+pub mod control;
 pub mod controllers;
+#[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+pub mod desktop;
+pub mod dialer;
+pub mod filter;
+pub mod history;
 #[cfg(any(target_os = "android", target_os = "ios"))]
 pub mod mobile;
+pub mod pairing;
+pub mod payload;
+pub mod peers;
+pub mod swarm_key;
 pub mod utils;
+pub mod watcher;
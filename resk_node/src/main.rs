@@ -1,7 +1,20 @@
+mod clipboard_sync;
+mod config;
+mod control;
 mod controllers;
+#[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+mod desktop;
+mod dialer;
+mod filter;
+mod history;
 #[cfg(any(target_os = "android", target_os = "ios"))]
 mod mobile;
+mod pairing;
+mod payload;
+mod peers;
+mod swarm_key;
 mod utils;
+mod watcher;
 
 use controllers::run_node;
 
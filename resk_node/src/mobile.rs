@@ -2,6 +2,8 @@
 use std::error::Error;
 use std::net::UdpSocket;
 
+use crate::clipboard_sync::ClipboardContent;
+
 pub struct MobileClipboard {
     socket: UdpSocket,
     port: i32,
@@ -38,4 +40,22 @@ impl MobileClipboard {
     ) -> Result<(), Box<dyn Error>> {
         Ok(())
     }
+    pub fn get_content(
+        &mut self,
+    ) -> Result<Option<ClipboardContent>, Box<dyn Error>> {
+        Ok(Some(ClipboardContent::Text(self.get_contents()?)))
+    }
+    // Only text can be shared with flutter
+    pub fn set_content(
+        &mut self,
+        content: &ClipboardContent,
+    ) -> Result<(), Box<dyn Error>> {
+        match content {
+            ClipboardContent::Text(text)
+            | ClipboardContent::Html { text, .. } => {
+                self.set_contents(text.clone())
+            }
+            _ => Err("Clipboard content is not supported on mobile".into()),
+        }
+    }
 }
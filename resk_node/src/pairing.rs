@@ -0,0 +1,618 @@
+// Interactive pairing of two nodes
+// Initiator commits to its nonce before it sees responder's one, so neither
+// side can pick a nonce to force a code. Then both users compare the same
+// short code and peers are trusted only after both of them confirmed it
+// Pairing started from a pairing uri skips comparing codes, one-time token
+// from the uri proves that user of the other node has seen it
+use libp2p::{Multiaddr, PeerId, StreamProtocol};
+use resk_proto::{PairingState, PairingSummary};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use tokio::time::{Duration, Instant};
+
+use crate::payload::content_hash;
+
+pub const PAIRING_PROTOCOL: StreamProtocol =
+    StreamProtocol::new("/resk/pair/1");
+// Time users have to compare codes
+const PAIRING_TIMEOUT: Duration = Duration::from_secs(300);
+// Pairings kept at once, so peers can not flood the map
+const MAX_PAIRINGS: usize = 16;
+const NO_PAIRING: &str = "No pairing in progress with this peer";
+const URI_PREFIX: &str = "resk://pair?";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum PairRequest {
+    // Hash of initiator nonce
+    Start {
+        commitment: String,
+        // One-time token from pairing uri
+        #[serde(default)]
+        token: Option<String>,
+    },
+    Reveal {
+        nonce: String,
+    },
+    // User accepted the code
+    Confirm,
+    Cancel,
+    // Sender forgot receiver, which should forget it as well
+    Unpair,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum PairResponse {
+    Started { nonce: String },
+    Ack,
+    Rejected { reason: String },
+}
+
+struct Pairing {
+    initiator: bool,
+    // Started with one-time token, codes are confirmed automatically
+    token: bool,
+    local_nonce: String,
+    // Responder keeps initiator commitment until nonce is revealed
+    commitment: Option<String>,
+    code: Option<String>,
+    local_confirmed: bool,
+    remote_confirmed: bool,
+    state: PairingState,
+    started: Instant,
+}
+
+pub struct Pairings {
+    local_peer_id: PeerId,
+    pairings: HashMap<PeerId, Pairing>,
+    // Peers paired since last take_completed
+    completed: Vec<PeerId>,
+    // One-time tokens of generated pairing uris
+    tokens: Vec<(String, Instant)>,
+}
+
+impl Pairings {
+    pub fn new(local_peer_id: PeerId) -> Self {
+        Pairings {
+            local_peer_id,
+            pairings: HashMap::new(),
+            completed: vec![],
+            tokens: vec![],
+        }
+    }
+
+    // Token for a new pairing uri
+    pub fn new_token(&mut self) -> String {
+        let token = hex::encode(rand::random::<[u8; 16]>());
+        self.tokens.push((token.clone(), Instant::now()));
+        token
+    }
+
+    // Start pairing with peer, returns request to send
+    // Token is set when pairing uri of peer is redeemed
+    pub fn initiate(
+        &mut self,
+        peer_id: PeerId,
+        token: Option<String>,
+    ) -> Result<PairRequest, Box<dyn Error>> {
+        // Code user may be comparing right now must not be replaced
+        if self.active(&peer_id).is_some() {
+            return Err("Pairing is already in progress".into());
+        }
+        let mut pairing = Pairing::new(true, None);
+        pairing.token = token.is_some();
+        let commitment = content_hash(pairing.local_nonce.as_bytes());
+        self.pairings.insert(peer_id, pairing);
+        Ok(PairRequest::Start { commitment, token })
+    }
+
+    pub fn handle_request(
+        &mut self,
+        peer_id: PeerId,
+        request: PairRequest,
+    ) -> PairResponse {
+        let local_peer_id = self.local_peer_id;
+        match request {
+            PairRequest::Start { commitment, token } => {
+                // Code user may be comparing right now must not be replaced
+                if self.active(&peer_id).is_some() {
+                    return rejected("Pairing is already in progress");
+                }
+                self.prune();
+                if !self.pairings.contains_key(&peer_id)
+                    && self.pairings.len() >= MAX_PAIRINGS
+                {
+                    return rejected("Too many pairings in progress");
+                }
+                let mut pairing = Pairing::new(false, Some(commitment));
+                if let Some(token) = token {
+                    if !self.take_token(&token) {
+                        return rejected("Invalid pairing token");
+                    }
+                    // User who generated the uri already agreed to pair
+                    pairing.token = true;
+                    pairing.local_confirmed = true;
+                }
+                let nonce = pairing.local_nonce.clone();
+                self.pairings.insert(peer_id, pairing);
+                log::info!("Pairing requested by {peer_id}");
+                PairResponse::Started { nonce }
+            }
+            PairRequest::Reveal { nonce } => {
+                let Some(pairing) = self.active(&peer_id) else {
+                    return rejected(NO_PAIRING);
+                };
+                if pairing.initiator || pairing.state != PairingState::Waiting {
+                    return rejected("Unexpected nonce");
+                }
+                if pairing.commitment.as_ref()
+                    != Some(&content_hash(nonce.as_bytes()))
+                {
+                    pairing.fail("Nonce does not match commitment");
+                    return rejected("Nonce does not match commitment");
+                }
+                pairing.code = Some(short_code(
+                    &peer_id,
+                    &local_peer_id,
+                    &nonce,
+                    &pairing.local_nonce,
+                ));
+                pairing.state = PairingState::Confirming;
+                PairResponse::Ack
+            }
+            PairRequest::Confirm => {
+                let Some(pairing) = self.active(&peer_id) else {
+                    return rejected(NO_PAIRING);
+                };
+                if pairing.state != PairingState::Confirming {
+                    return rejected("Pairing code is not known yet");
+                }
+                pairing.remote_confirmed = true;
+                self.complete(peer_id);
+                PairResponse::Ack
+            }
+            PairRequest::Cancel => {
+                self.fail(&peer_id, "Rejected by peer");
+                PairResponse::Ack
+            }
+            PairRequest::Unpair => {
+                self.pairings.remove(&peer_id);
+                PairResponse::Ack
+            }
+        }
+    }
+
+    // Returns next request to send, if any
+    pub fn handle_response(
+        &mut self,
+        peer_id: PeerId,
+        response: PairResponse,
+    ) -> Option<PairRequest> {
+        let local_peer_id = self.local_peer_id;
+        let pairing = self.active(&peer_id)?;
+        match response {
+            PairResponse::Started { nonce } => {
+                if !pairing.initiator || pairing.state != PairingState::Waiting
+                {
+                    return None;
+                }
+                pairing.code = Some(short_code(
+                    &local_peer_id,
+                    &peer_id,
+                    &pairing.local_nonce,
+                    &nonce,
+                ));
+                pairing.state = PairingState::Confirming;
+                Some(PairRequest::Reveal {
+                    nonce: pairing.local_nonce.clone(),
+                })
+            }
+            // Token pairings are confirmed right after nonces were exchanged
+            PairResponse::Ack if pairing.token => {
+                if pairing.state != PairingState::Confirming {
+                    return None;
+                }
+                if !pairing.local_confirmed {
+                    pairing.local_confirmed = true;
+                    return Some(PairRequest::Confirm);
+                }
+                // Responder accepted the token, so it has confirmed as well
+                pairing.remote_confirmed = true;
+                self.complete(peer_id);
+                None
+            }
+            PairResponse::Ack => None,
+            PairResponse::Rejected { reason } => {
+                pairing.fail(&reason);
+                None
+            }
+        }
+    }
+
+    // Local user accepted the code, returns request to send
+    pub fn confirm(
+        &mut self,
+        peer_id: PeerId,
+    ) -> Result<PairRequest, Box<dyn Error>> {
+        let pairing = self.active(&peer_id).ok_or(NO_PAIRING)?;
+        if pairing.state != PairingState::Confirming {
+            return Err("Pairing code is not known yet".into());
+        }
+        pairing.local_confirmed = true;
+        self.complete(peer_id);
+        Ok(PairRequest::Confirm)
+    }
+
+    // Local user rejected the code, returns request to send
+    pub fn reject(
+        &mut self,
+        peer_id: PeerId,
+    ) -> Result<PairRequest, Box<dyn Error>> {
+        let pairing = self.active(&peer_id).ok_or(NO_PAIRING)?;
+        pairing.fail("Rejected");
+        Ok(PairRequest::Cancel)
+    }
+
+    pub fn fail(&mut self, peer_id: &PeerId, reason: &str) {
+        if let Some(pairing) = self.active(peer_id) {
+            pairing.fail(reason);
+        }
+    }
+
+    // Peers that were paired since last call and have to be trusted
+    pub fn take_completed(&mut self) -> Vec<PeerId> {
+        std::mem::take(&mut self.completed)
+    }
+
+    pub fn list(&mut self) -> Vec<PairingSummary> {
+        let peers: Vec<PeerId> = self.pairings.keys().copied().collect();
+        // Refresh state of expired pairings
+        peers.iter().for_each(|peer_id| {
+            self.active(peer_id);
+        });
+        self.pairings
+            .iter()
+            .map(|(peer_id, pairing)| PairingSummary {
+                peer_id: peer_id.to_string(),
+                name: None,
+                initiator: pairing.initiator,
+                code: pairing.code.clone(),
+                local_confirmed: pairing.local_confirmed,
+                remote_confirmed: pairing.remote_confirmed,
+                state: pairing.state.clone(),
+            })
+            .collect()
+    }
+
+    fn take_token(&mut self, token: &str) -> bool {
+        self.tokens
+            .retain(|(_, created)| created.elapsed() < PAIRING_TIMEOUT);
+        let found = self.tokens.iter().any(|(known, _)| known == token);
+        self.tokens.retain(|(known, _)| known != token);
+        found
+    }
+
+    // Forget pairings that were started too long ago
+    fn prune(&mut self) {
+        self.pairings
+            .retain(|_, pairing| pairing.started.elapsed() <= PAIRING_TIMEOUT);
+    }
+
+    // Pairing with peer that is still in progress
+    fn active(&mut self, peer_id: &PeerId) -> Option<&mut Pairing> {
+        let pairing = self.pairings.get_mut(peer_id)?;
+        if !matches!(
+            pairing.state,
+            PairingState::Waiting | PairingState::Confirming
+        ) {
+            return None;
+        }
+        if pairing.started.elapsed() > PAIRING_TIMEOUT {
+            pairing.fail("Pairing timed out");
+            return None;
+        }
+        Some(pairing)
+    }
+
+    fn complete(&mut self, peer_id: PeerId) {
+        if let Some(pairing) = self.pairings.get_mut(&peer_id) {
+            if pairing.local_confirmed && pairing.remote_confirmed {
+                pairing.state = PairingState::Paired;
+                self.completed.push(peer_id);
+            }
+        }
+    }
+}
+
+impl Pairing {
+    fn new(initiator: bool, commitment: Option<String>) -> Self {
+        Pairing {
+            initiator,
+            token: false,
+            local_nonce: hex::encode(rand::random::<[u8; 32]>()),
+            commitment,
+            code: None,
+            local_confirmed: false,
+            remote_confirmed: false,
+            state: PairingState::Waiting,
+            started: Instant::now(),
+        }
+    }
+
+    fn fail(&mut self, reason: &str) {
+        log::info!("Pairing failed: {reason}");
+        self.state = PairingState::Failed(reason.to_string());
+    }
+}
+
+fn rejected(reason: &str) -> PairResponse {
+    PairResponse::Rejected {
+        reason: reason.to_string(),
+    }
+}
+
+// Six digit code derived from both peer ids and nonces
+fn short_code(
+    initiator: &PeerId,
+    responder: &PeerId,
+    initiator_nonce: &str,
+    responder_nonce: &str,
+) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(initiator.to_bytes());
+    hasher.update(responder.to_bytes());
+    hasher.update(initiator_nonce.as_bytes());
+    hasher.update(responder_nonce.as_bytes());
+    let digest = hasher.finalize();
+    let number =
+        u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]);
+    format!("{:06}", number % 1_000_000)
+}
+
+// Everything another node needs to pair with this one
+// resk://pair?peer=<peer id>&addr=<multiaddr>&token=<one-time token>
+pub struct PairingUri {
+    pub peer_id: PeerId,
+    pub addr: Multiaddr,
+    pub token: String,
+}
+
+impl fmt::Display for PairingUri {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{URI_PREFIX}peer={}&addr={}&token={}",
+            self.peer_id, self.addr, self.token
+        )
+    }
+}
+
+impl FromStr for PairingUri {
+    type Err = Box<dyn Error>;
+
+    fn from_str(uri: &str) -> Result<Self, Self::Err> {
+        let query = uri
+            .trim()
+            .strip_prefix(URI_PREFIX)
+            .ok_or("Not a resk pairing uri")?;
+        let mut params: HashMap<&str, &str> = HashMap::new();
+        for param in query.split('&') {
+            if let Some((key, value)) = param.split_once('=') {
+                params.insert(key, value);
+            }
+        }
+        let param = |key: &str| {
+            params
+                .get(key)
+                .copied()
+                .ok_or(format!("Pairing uri has no {key}"))
+        };
+        Ok(PairingUri {
+            peer_id: PeerId::from_str(param("peer")?)?,
+            addr: Multiaddr::from_str(param("addr")?)?,
+            token: param("token")?.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs initiator and responder until nonces are exchanged
+    fn exchange_nonces(
+        initiator: &mut Pairings,
+        responder: &mut Pairings,
+        token: Option<String>,
+    ) -> PairResponse {
+        let (a, b) = (initiator.local_peer_id, responder.local_peer_id);
+        let start = initiator.initiate(b, token).unwrap();
+        let started = responder.handle_request(a, start);
+        let Some(reveal) = initiator.handle_response(b, started.clone()) else {
+            return started;
+        };
+        let ack = responder.handle_request(a, reveal);
+        if let Some(request) = initiator.handle_response(b, ack.clone()) {
+            let ack = responder.handle_request(a, request);
+            initiator.handle_response(b, ack);
+        }
+        started
+    }
+
+    fn summary(pairings: &mut Pairings, peer_id: &PeerId) -> PairingSummary {
+        pairings
+            .list()
+            .into_iter()
+            .find(|pairing| pairing.peer_id == peer_id.to_string())
+            .unwrap()
+    }
+
+    #[test]
+    fn pairs_after_both_users_confirm_same_code() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let (mut initiator, mut responder) =
+            (Pairings::new(a), Pairings::new(b));
+        exchange_nonces(&mut initiator, &mut responder, None);
+
+        let code = summary(&mut initiator, &b).code;
+        assert!(code.is_some());
+        assert_eq!(code, summary(&mut responder, &a).code);
+        assert_eq!(summary(&mut responder, &a).state, PairingState::Confirming);
+
+        let confirm = initiator.confirm(b).unwrap();
+        responder.handle_request(a, confirm);
+        assert!(responder.take_completed().is_empty());
+        let confirm = responder.confirm(a).unwrap();
+        initiator.handle_request(b, confirm);
+
+        assert_eq!(initiator.take_completed(), vec![b]);
+        assert_eq!(responder.take_completed(), vec![a]);
+        assert_eq!(summary(&mut initiator, &b).state, PairingState::Paired);
+    }
+
+    #[test]
+    fn token_pairing_completes_without_confirmation() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let (mut initiator, mut responder) =
+            (Pairings::new(a), Pairings::new(b));
+        let token = responder.new_token();
+        exchange_nonces(&mut initiator, &mut responder, Some(token.clone()));
+
+        assert_eq!(initiator.take_completed(), vec![b]);
+        assert_eq!(responder.take_completed(), vec![a]);
+
+        // Tokens are single use
+        let mut other = Pairings::new(PeerId::random());
+        let response = exchange_nonces(&mut other, &mut responder, Some(token));
+        assert!(matches!(response, PairResponse::Rejected { .. }));
+    }
+
+    #[test]
+    fn rejects_unknown_token() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let (mut initiator, mut responder) =
+            (Pairings::new(a), Pairings::new(b));
+        let response = exchange_nonces(
+            &mut initiator,
+            &mut responder,
+            Some("unknown".to_string()),
+        );
+        assert!(matches!(response, PairResponse::Rejected { .. }));
+        assert!(matches!(
+            summary(&mut initiator, &b).state,
+            PairingState::Failed(_)
+        ));
+    }
+
+    #[test]
+    fn fails_if_nonce_does_not_match_commitment() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let mut responder = Pairings::new(b);
+        let start = PairRequest::Start {
+            commitment: content_hash(b"committed"),
+            token: None,
+        };
+        responder.handle_request(a, start);
+        let reveal = PairRequest::Reveal {
+            nonce: "other".to_string(),
+        };
+        let response = responder.handle_request(a, reveal);
+        assert!(matches!(response, PairResponse::Rejected { .. }));
+        assert!(matches!(
+            summary(&mut responder, &a).state,
+            PairingState::Failed(_)
+        ));
+    }
+
+    #[test]
+    fn start_does_not_replace_pairing_in_progress() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let (mut initiator, mut responder) =
+            (Pairings::new(a), Pairings::new(b));
+        exchange_nonces(&mut initiator, &mut responder, None);
+        let code = summary(&mut responder, &a).code;
+
+        let restart = Pairings::new(a).initiate(b, None).unwrap();
+        let response = responder.handle_request(a, restart);
+        assert!(matches!(response, PairResponse::Rejected { .. }));
+        assert_eq!(summary(&mut responder, &a).code, code);
+        assert_eq!(summary(&mut responder, &a).state, PairingState::Confirming);
+    }
+
+    #[test]
+    fn initiate_does_not_replace_pairing_in_progress() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let (mut initiator, mut responder) =
+            (Pairings::new(a), Pairings::new(b));
+        exchange_nonces(&mut initiator, &mut responder, None);
+        let code = summary(&mut responder, &a).code;
+
+        assert!(responder.initiate(a, None).is_err());
+        assert!(initiator.initiate(b, None).is_err());
+        assert_eq!(summary(&mut responder, &a).code, code);
+        assert_eq!(summary(&mut responder, &a).state, PairingState::Confirming);
+
+        // Peer can be paired again once pairing is over
+        responder.reject(a).unwrap();
+        assert!(responder.initiate(a, None).is_ok());
+    }
+
+    #[test]
+    fn caps_pairings_in_progress() {
+        let mut responder = Pairings::new(PeerId::random());
+        let start = || PairRequest::Start {
+            commitment: content_hash(b"nonce"),
+            token: None,
+        };
+        for _ in 0..MAX_PAIRINGS {
+            let response = responder.handle_request(PeerId::random(), start());
+            assert!(matches!(response, PairResponse::Started { .. }));
+        }
+        let response = responder.handle_request(PeerId::random(), start());
+        assert!(matches!(response, PairResponse::Rejected { .. }));
+        assert_eq!(responder.list().len(), MAX_PAIRINGS);
+    }
+
+    #[test]
+    fn short_code_is_six_digits_of_both_nonces() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let code = short_code(&a, &b, "first", "second");
+        assert_eq!(code.len(), 6);
+        assert!(code.chars().all(|c| c.is_ascii_digit()));
+        assert_eq!(code, short_code(&a, &b, "first", "second"));
+        assert_ne!(code, short_code(&a, &b, "first", "third"));
+        assert_ne!(code, short_code(&b, &a, "first", "second"));
+    }
+
+    #[test]
+    fn pairing_uri_round_trip() {
+        let uri = PairingUri {
+            peer_id: PeerId::random(),
+            addr: "/ip4/192.168.1.2/tcp/4001".parse().unwrap(),
+            token: "abc123".to_string(),
+        };
+        let parsed = PairingUri::from_str(&format!(" {uri}\n")).unwrap();
+        assert_eq!(parsed.peer_id, uri.peer_id);
+        assert_eq!(parsed.addr, uri.addr);
+        assert_eq!(parsed.token, uri.token);
+    }
+
+    #[test]
+    fn rejects_invalid_pairing_uri() {
+        let peer_id = PeerId::random();
+        for uri in [
+            format!(
+                "https://pair?peer={peer_id}&addr=/ip4/1.2.3.4/tcp/1&token=t"
+            ),
+            format!("resk://pair?peer={peer_id}&addr=/ip4/1.2.3.4/tcp/1"),
+            format!("resk://pair?peer={peer_id}&addr=nowhere&token=t"),
+            "resk://pair?peer=nobody&addr=/ip4/1.2.3.4/tcp/1&token=t"
+                .to_string(),
+        ] {
+            assert!(PairingUri::from_str(&uri).is_err(), "{uri}");
+        }
+    }
+}
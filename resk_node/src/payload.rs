@@ -0,0 +1,557 @@
+// Format of clipboard updates pushed to peers
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use libp2p::{request_response, PeerId, StreamProtocol};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::sync::Arc;
+use std::{io, mem};
+use tokio::sync::Semaphore;
+
+use crate::clipboard_sync::ClipboardContent;
+use crate::peers::TrustedPeers;
+use crate::utils::unix_millis;
+
+// Bumped on incompatible changes of ClipboardUpdate
+pub const PAYLOAD_VERSION: u8 = 1;
+// Raw text messages sent by nodes before ClipboardUpdate was introduced
+pub const LEGACY_VERSION: u8 = 0;
+
+// Gossipsub topic older nodes publish raw text on
+pub const UPDATE_TOPIC: &str = "resk-update";
+
+pub const MIME_TEXT: &str = "text/plain;charset=utf-8";
+pub const MIME_HTML: &str = "text/html";
+pub const MIME_PNG: &str = "image/png";
+
+// Protocol updates are pushed to trusted peers with
+// Streams are authenticated, so only the intended peer gets the content
+pub const CLIPBOARD_PROTOCOL: StreamProtocol =
+    StreamProtocol::new("/resk/clipboard/2");
+// Largest update accepted over direct transfer, fits big screenshots
+pub const MAX_TRANSFER_SIZE: usize = 64 * 1024 * 1024;
+// Json part of a direct transfer, everything but payloads
+const MAX_HEADER_SIZE: usize = 64 * 1024;
+// Transfers whose payloads are read at once, from all peers together,
+// so memory taken by them stays within a few MAX_TRANSFER_SIZE
+const MAX_INBOUND_TRANSFERS: usize = 4;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClipboardUpdate {
+    pub version: u8,
+    // MIME type of payload
+    pub mime: String,
+    // Peer id of the node where content was copied
+    pub origin: String,
+    // Unix time in milliseconds
+    pub timestamp: u64,
+    // Grows with every update from the same origin
+    pub seq: u64,
+    // Hex encoded sha256 of payload and alternatives
+    pub hash: String,
+    #[serde(with = "base64_bytes")]
+    pub payload: Vec<u8>,
+    // Richer representations of the same copy
+    // Payload always holds the most basic one, e.g. text/plain for html
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alternatives: Vec<Representation>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Representation {
+    pub mime: String,
+    #[serde(with = "base64_bytes")]
+    pub payload: Vec<u8>,
+}
+
+impl ClipboardUpdate {
+    pub fn new(origin: &PeerId, seq: u64, content: &ClipboardContent) -> Self {
+        let payload = content.as_bytes().to_vec();
+        let alternatives = match content {
+            ClipboardContent::Html { html, .. } => vec![Representation {
+                mime: MIME_HTML.to_string(),
+                payload: html.as_bytes().to_vec(),
+            }],
+            _ => vec![],
+        };
+        ClipboardUpdate {
+            version: PAYLOAD_VERSION,
+            mime: content.mime().to_string(),
+            origin: origin.to_string(),
+            timestamp: unix_millis(),
+            seq,
+            hash: update_hash(&payload, &alternatives),
+            payload,
+            alternatives,
+        }
+    }
+
+    // Size of all representations
+    pub fn size(&self) -> usize {
+        self.alternatives
+            .iter()
+            .fold(self.payload.len(), |size, alt| size + alt.payload.len())
+    }
+
+    // Decode gossipsub message data
+    // Anything that is not a ClipboardUpdate is treated as legacy raw text
+    pub fn decode(
+        data: &[u8],
+        source: Option<PeerId>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let source = source.ok_or("Update is not signed")?;
+        let update = match serde_json::from_slice::<ClipboardUpdate>(data) {
+            Ok(update) => update,
+            Err(_) => {
+                return Ok(ClipboardUpdate {
+                    version: LEGACY_VERSION,
+                    mime: MIME_TEXT.to_string(),
+                    origin: source.to_string(),
+                    timestamp: unix_millis(),
+                    seq: 0,
+                    hash: content_hash(data),
+                    payload: data.to_vec(),
+                    alternatives: vec![],
+                })
+            }
+        };
+        update.verify(&source)?;
+        Ok(update)
+    }
+
+    // Check update received from author before it is applied
+    // Origin has to be the author, so peers can not speak for others
+    // Legacy version is only given to raw text, it carries no ordering
+    pub fn verify(&self, author: &PeerId) -> Result<(), Box<dyn Error>> {
+        if self.version == LEGACY_VERSION {
+            return Err("Legacy updates are only taken as raw text".into());
+        }
+        if self.version > PAYLOAD_VERSION {
+            return Err(
+                format!("Unsupported update version {}", self.version).into()
+            );
+        }
+        if self.origin != author.to_string() {
+            return Err(format!(
+                "Update of {} was sent by {author}",
+                self.origin
+            )
+            .into());
+        }
+        if self.hash != update_hash(&self.payload, &self.alternatives) {
+            return Err("Update payload does not match its hash".into());
+        }
+        Ok(())
+    }
+
+    // Content if payload is of supported type
+    pub fn content(&self) -> Option<ClipboardContent> {
+        if self.mime.starts_with("text/plain") {
+            let text = String::from_utf8_lossy(&self.payload).to_string();
+            let html =
+                self.alternatives.iter().find(|alt| alt.mime == MIME_HTML);
+            return Some(match html {
+                Some(html) => ClipboardContent::Html {
+                    html: String::from_utf8_lossy(&html.payload).to_string(),
+                    text,
+                },
+                None => ClipboardContent::Text(text),
+            });
+        }
+        if self.mime == MIME_PNG {
+            return Some(ClipboardContent::Image(self.payload.clone()));
+        }
+        None
+    }
+}
+
+// Answer to a pushed update
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PushResponse {
+    // False if peer does not take updates from this node
+    pub accepted: bool,
+}
+
+// Direct transfers are a json header followed by raw payloads,
+// so big images are not inflated by base64
+#[derive(Clone)]
+pub struct TransferCodec {
+    // Payloads are only read for updates of trusted origins
+    trusted_peers: TrustedPeers,
+    // Shared by clones of codec on every connection
+    inbound: Arc<Semaphore>,
+}
+
+impl TransferCodec {
+    pub fn new(trusted_peers: TrustedPeers) -> Self {
+        TransferCodec {
+            trusted_peers,
+            inbound: Arc::new(Semaphore::new(MAX_INBOUND_TRANSFERS)),
+        }
+    }
+}
+
+#[async_trait]
+impl request_response::Codec for TransferCodec {
+    type Protocol = StreamProtocol;
+    type Request = ClipboardUpdate;
+    type Response = PushResponse;
+
+    async fn read_request<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+    ) -> io::Result<ClipboardUpdate>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let header = read_chunk(io, MAX_HEADER_SIZE).await?;
+        let mut update: ClipboardUpdate = serde_json::from_slice(&header)?;
+        // Codec does not know the sender, but origin has to be the sender
+        // for update to be accepted, so anything else is dropped here
+        // before any payload is read
+        let trusted = update
+            .origin
+            .parse()
+            .is_ok_and(|origin| self.trusted_peers.contains(&origin));
+        if !trusted {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "Update of {} is not from a trusted peer",
+                    update.origin
+                ),
+            ));
+        }
+        let _permit = self.inbound.acquire().await.map_err(io::Error::other)?;
+        read_payloads(io, &mut update).await?;
+        Ok(update)
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+    ) -> io::Result<PushResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let response = read_chunk(io, MAX_HEADER_SIZE).await?;
+        Ok(serde_json::from_slice(&response)?)
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        mut update: ClipboardUpdate,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let payloads = take_payloads(&mut update);
+        write_chunk(io, &serde_json::to_vec(&update)?).await?;
+        for payload in payloads {
+            write_chunk(io, &payload).await?;
+        }
+        io.close().await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        response: PushResponse,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_chunk(io, &serde_json::to_vec(&response)?).await?;
+        io.close().await
+    }
+}
+
+// Payloads are left out of json header and sent after it
+fn take_payloads(update: &mut ClipboardUpdate) -> Vec<Vec<u8>> {
+    let mut payloads = vec![mem::take(&mut update.payload)];
+    payloads.extend(
+        update
+            .alternatives
+            .iter_mut()
+            .map(|alt| mem::take(&mut alt.payload)),
+    );
+    payloads
+}
+
+async fn read_payloads<T: AsyncRead + Unpin>(
+    io: &mut T,
+    update: &mut ClipboardUpdate,
+) -> io::Result<()> {
+    let mut budget = MAX_TRANSFER_SIZE;
+    update.payload = read_chunk(io, budget).await?;
+    budget -= update.payload.len();
+    for alt in update.alternatives.iter_mut() {
+        alt.payload = read_chunk(io, budget).await?;
+        budget -= alt.payload.len();
+    }
+    Ok(())
+}
+
+async fn read_chunk<T: AsyncRead + Unpin>(
+    io: &mut T,
+    limit: usize,
+) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    io.read_exact(&mut len).await?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > limit {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Transfer of {len} bytes is too big"),
+        ));
+    }
+    // Grows with data actually received, not with announced length
+    let mut chunk = Vec::new();
+    (&mut *io).take(len as u64).read_to_end(&mut chunk).await?;
+    if chunk.len() != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(chunk)
+}
+
+async fn write_chunk<T: AsyncWrite + Unpin>(
+    io: &mut T,
+    chunk: &[u8],
+) -> io::Result<()> {
+    let len = u32::try_from(chunk.len()).map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidInput, "Transfer is too big")
+    })?;
+    io.write_all(&len.to_be_bytes()).await?;
+    io.write_all(chunk).await
+}
+
+pub fn content_hash(content: &[u8]) -> String {
+    hex::encode(Sha256::digest(content))
+}
+
+// Alternatives are hashed too, so html can not be swapped unnoticed
+// Updates without them keep plain hash of payload, as older nodes expect
+fn update_hash(payload: &[u8], alternatives: &[Representation]) -> String {
+    if alternatives.is_empty() {
+        return content_hash(payload);
+    }
+    let mut hasher = Sha256::new();
+    let parts = alternatives
+        .iter()
+        .flat_map(|alt| [alt.mime.as_bytes(), &alt.payload]);
+    for part in std::iter::once(payload).chain(parts) {
+        // Lengths keep parts from being shifted between each other
+        hasher.update((part.len() as u64).to_be_bytes());
+        hasher.update(part);
+    }
+    hex::encode(hasher.finalize())
+}
+
+// Bytes are shared as base64 to keep messages compact in json
+mod base64_bytes {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        bytes: &[u8],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        STANDARD.decode(encoded).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::io::Cursor;
+    use request_response::Codec;
+
+    fn codec(trusted: &[PeerId]) -> TransferCodec {
+        TransferCodec::new(trusted.iter().copied().collect())
+    }
+
+    fn html() -> ClipboardContent {
+        ClipboardContent::Html {
+            html: "<b>bold</b>".to_string(),
+            text: "bold".to_string(),
+        }
+    }
+
+    #[test]
+    fn decodes_update_of_its_author() {
+        let author = PeerId::random();
+        let update = ClipboardUpdate::new(&author, 3, &html());
+        let data = serde_json::to_vec(&update).unwrap();
+        let decoded = ClipboardUpdate::decode(&data, Some(author)).unwrap();
+        assert_eq!(decoded.seq, 3);
+        assert_eq!(decoded.content(), Some(html()));
+    }
+
+    #[test]
+    fn raw_text_is_legacy_update_of_source() {
+        let source = PeerId::random();
+        let update =
+            ClipboardUpdate::decode(b"plain text", Some(source)).unwrap();
+        assert_eq!(update.version, LEGACY_VERSION);
+        assert_eq!(update.origin, source.to_string());
+        assert_eq!(update.hash, content_hash(b"plain text"));
+        assert_eq!(
+            update.content(),
+            Some(ClipboardContent::Text("plain text".to_string()))
+        );
+        assert!(ClipboardUpdate::decode(b"plain text", None).is_err());
+    }
+
+    #[test]
+    fn rejects_updates_that_do_not_verify() {
+        let author = PeerId::random();
+        let update = ClipboardUpdate::new(&author, 1, &html());
+        assert!(update.verify(&author).is_ok());
+        // Sent by someone else
+        assert!(update.verify(&PeerId::random()).is_err());
+
+        let mut newer = update.clone();
+        newer.version = PAYLOAD_VERSION + 1;
+        assert!(newer.verify(&author).is_err());
+
+        let mut legacy = update.clone();
+        legacy.version = LEGACY_VERSION;
+        assert!(legacy.verify(&author).is_err());
+
+        let mut swapped = update.clone();
+        swapped.alternatives[0].payload = b"<i>other</i>".to_vec();
+        assert!(swapped.verify(&author).is_err());
+
+        let mut tampered = update;
+        tampered.payload = b"other".to_vec();
+        assert!(tampered.verify(&author).is_err());
+    }
+
+    #[test]
+    fn hash_covers_alternatives() {
+        let plain = update_hash(b"bold", &[]);
+        assert_eq!(plain, content_hash(b"bold"));
+        let html = update_hash(
+            b"bold",
+            &[Representation {
+                mime: MIME_HTML.to_string(),
+                payload: b"<b>bold</b>".to_vec(),
+            }],
+        );
+        assert_ne!(html, plain);
+        // Bytes moved between parts change the hash
+        let shifted = update_hash(
+            b"bold<",
+            &[Representation {
+                mime: MIME_HTML.to_string(),
+                payload: b"b>bold</b>".to_vec(),
+            }],
+        );
+        assert_ne!(html, shifted);
+    }
+
+    #[test]
+    fn transfer_round_trip() {
+        let author = PeerId::random();
+        let update = ClipboardUpdate::new(&author, 7, &html());
+        let mut codec = codec(&[author]);
+        let mut io = Cursor::new(Vec::new());
+        block_on(codec.write_request(
+            &CLIPBOARD_PROTOCOL,
+            &mut io,
+            update.clone(),
+        ))
+        .unwrap();
+        io.set_position(0);
+        let received =
+            block_on(codec.read_request(&CLIPBOARD_PROTOCOL, &mut io)).unwrap();
+        assert!(received.verify(&author).is_ok());
+        assert_eq!(received.payload, update.payload);
+        assert_eq!(received.content(), Some(html()));
+
+        let mut io = Cursor::new(Vec::new());
+        block_on(codec.write_response(
+            &CLIPBOARD_PROTOCOL,
+            &mut io,
+            PushResponse { accepted: true },
+        ))
+        .unwrap();
+        io.set_position(0);
+        let response =
+            block_on(codec.read_response(&CLIPBOARD_PROTOCOL, &mut io))
+                .unwrap();
+        assert!(response.accepted);
+    }
+
+    #[test]
+    fn rejects_oversized_chunks() {
+        let mut io = Cursor::new(Vec::new());
+        block_on(write_chunk(&mut io, &[0; 16])).unwrap();
+        io.set_position(0);
+        assert!(block_on(read_chunk(&mut io, 15)).is_err());
+
+        // Header claiming a huge payload
+        let author = PeerId::random();
+        let mut io = header_only(&author);
+        let len = (MAX_TRANSFER_SIZE as u32 + 1).to_be_bytes();
+        block_on(io.write_all(&len)).unwrap();
+        io.set_position(0);
+        let mut codec = codec(&[author]);
+        assert!(
+            block_on(codec.read_request(&CLIPBOARD_PROTOCOL, &mut io)).is_err()
+        );
+
+        // Announced length is not allocated before data arrives
+        let mut io = Cursor::new(Vec::new());
+        block_on(io.write_all(&1024u32.to_be_bytes())).unwrap();
+        block_on(io.write_all(&[0; 16])).unwrap();
+        io.set_position(0);
+        let err = block_on(read_chunk(&mut io, 1024)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn payloads_of_untrusted_origins_are_not_read() {
+        let author = PeerId::random();
+        let mut io = header_only(&author);
+        // Payload that would be read next
+        block_on(write_chunk(&mut io, b"text")).unwrap();
+        io.set_position(0);
+        let mut codec = codec(&[]);
+        let err = block_on(codec.read_request(&CLIPBOARD_PROTOCOL, &mut io))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        let header_len =
+            4 + u32::from_be_bytes(io.get_ref()[..4].try_into().unwrap())
+                as u64;
+        assert_eq!(io.position(), header_len);
+    }
+
+    // Stream with json header of a text update from author
+    fn header_only(author: &PeerId) -> Cursor<Vec<u8>> {
+        let mut header = ClipboardUpdate::new(
+            author,
+            1,
+            &ClipboardContent::Text("text".to_string()),
+        );
+        take_payloads(&mut header);
+        let mut io = Cursor::new(Vec::new());
+        block_on(write_chunk(&mut io, &serde_json::to_vec(&header).unwrap()))
+            .unwrap();
+        io
+    }
+}
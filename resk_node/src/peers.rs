@@ -0,0 +1,510 @@
+// Trusted peers and names of peers shown to users
+use libp2p::{Multiaddr, PeerId, StreamProtocol};
+use resk_proto::{PeerInfo, PeerPolicy};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use crate::utils::{load_data_map, unix_millis, write_json};
+
+// Exchange of device info right after peers connect
+pub const INFO_PROTOCOL: StreamProtocol = StreamProtocol::new("/resk/info/1");
+// Addresses kept for every peer, newest first
+const MAX_PEER_ADDRS: usize = 5;
+// Advertised names are cut to this many characters
+const MAX_NAME_LENGTH: usize = 64;
+// Names of untrusted peers kept at once
+const MAX_NAMES: usize = 256;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeviceInfo {
+    pub name: String,
+}
+
+// Trusted peer as stored in peers array of data.json
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PeerRecord {
+    pub peer_id: PeerId,
+    // Advertised by peer itself
+    #[serde(default)]
+    pub name: Option<String>,
+    // Set by local user
+    #[serde(default)]
+    pub alias: Option<String>,
+    // Unix time in milliseconds, None for peers paired by older versions
+    #[serde(default)]
+    pub paired_at: Option<u64>,
+    #[serde(default)]
+    pub last_seen: Option<u64>,
+    // Addresses peer was last reached on
+    #[serde(default)]
+    pub addrs: Vec<Multiaddr>,
+    #[serde(default)]
+    pub policy: PeerPolicy,
+}
+
+impl PeerRecord {
+    fn new(peer_id: PeerId) -> Self {
+        PeerRecord {
+            peer_id,
+            name: None,
+            alias: None,
+            paired_at: None,
+            last_seen: None,
+            addrs: vec![],
+            policy: PeerPolicy::default(),
+        }
+    }
+
+    // Alias wins over advertised name
+    pub fn display_name(&self) -> Option<&str> {
+        self.alias.as_deref().or(self.name.as_deref())
+    }
+
+    pub fn info(&self) -> PeerInfo {
+        PeerInfo {
+            peer_id: self.peer_id.to_string(),
+            name: self.name.clone(),
+            alias: self.alias.clone(),
+            paired_at: self.paired_at,
+            last_seen: self.last_seen,
+            addrs: self.addrs.iter().map(Multiaddr::to_string).collect(),
+            policy: self.policy,
+        }
+    }
+}
+
+// Ids of trusted peers that can be checked outside of the event loop,
+// e.g. by relay server deciding whom to serve
+#[derive(Clone, Default)]
+pub struct TrustedPeers(Arc<RwLock<HashSet<PeerId>>>);
+
+impl FromIterator<PeerId> for TrustedPeers {
+    fn from_iter<I: IntoIterator<Item = PeerId>>(peers: I) -> Self {
+        TrustedPeers(Arc::new(RwLock::new(peers.into_iter().collect())))
+    }
+}
+
+impl TrustedPeers {
+    pub fn contains(&self, peer_id: &PeerId) -> bool {
+        self.0
+            .read()
+            .map(|peers| peers.contains(peer_id))
+            .unwrap_or(false)
+    }
+}
+
+pub struct PeerStore {
+    // data.json, peers are kept next to other node data
+    path: PathBuf,
+    // Trusted peers in order they were paired
+    records: Vec<PeerRecord>,
+    // Names advertised by peers that are not trusted
+    names: HashMap<PeerId, String>,
+    // Kept in sync with records
+    trusted_peers: TrustedPeers,
+}
+
+impl PeerStore {
+    pub fn load(data_dir: &Path) -> Result<Self, Box<dyn Error>> {
+        let path = data_dir.join("data.json");
+        let mut data_map = load_data_map(&path)?;
+        let mut store = PeerStore {
+            path,
+            records: vec![],
+            names: HashMap::new(),
+            trusted_peers: TrustedPeers::default(),
+        };
+        let peers = match data_map.get("peers") {
+            Some(Value::Array(peers)) => peers.clone(),
+            _ => vec![],
+        };
+        for peer in peers {
+            match parse_record(peer) {
+                Ok(record) if store.is_trusted(&record.peer_id) => {}
+                Ok(record) => store.records.push(record),
+                Err(err) => log::error!("Skipping invalid peer entry: {err}"),
+            }
+        }
+        // Policies and names used to be kept in separate maps
+        let policies = data_map.remove("peer_policies");
+        let names = data_map.remove("peer_names");
+        if policies.is_some() || names.is_some() {
+            store.migrate(policies, names);
+            store.save()?;
+        }
+        store.sync_trusted();
+        Ok(store)
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        let mut data_map = load_data_map(&self.path)?;
+        data_map
+            .insert("peers".to_string(), serde_json::to_value(&self.records)?);
+        data_map.remove("peer_policies");
+        data_map.remove("peer_names");
+        write_json(&self.path, &data_map)?;
+        Ok(())
+    }
+
+    pub fn records(&self) -> &[PeerRecord] {
+        &self.records
+    }
+
+    pub fn trusted(&self) -> impl Iterator<Item = &PeerId> {
+        self.records.iter().map(|record| &record.peer_id)
+    }
+
+    pub fn trusted_peers(&self) -> TrustedPeers {
+        self.trusted_peers.clone()
+    }
+
+    fn sync_trusted(&self) {
+        if let Ok(mut peers) = self.trusted_peers.0.write() {
+            *peers = self.trusted().copied().collect();
+        }
+    }
+
+    pub fn is_trusted(&self, peer_id: &PeerId) -> bool {
+        self.get(peer_id).is_some()
+    }
+
+    pub fn get(&self, peer_id: &PeerId) -> Option<&PeerRecord> {
+        self.records
+            .iter()
+            .find(|record| record.peer_id == *peer_id)
+    }
+
+    pub fn get_mut(&mut self, peer_id: &PeerId) -> Option<&mut PeerRecord> {
+        self.records
+            .iter_mut()
+            .find(|record| record.peer_id == *peer_id)
+    }
+
+    // Peers that are not trusted are neither sent to nor received from
+    pub fn policy(&self, peer_id: &PeerId) -> PeerPolicy {
+        self.get(peer_id)
+            .map(|record| record.policy)
+            .unwrap_or(PeerPolicy::None)
+    }
+
+    // Record newly paired peer, returns false if it was already trusted
+    pub fn add(&mut self, peer_id: PeerId) -> bool {
+        if self.is_trusted(&peer_id) {
+            return false;
+        }
+        let mut record = PeerRecord::new(peer_id);
+        record.name = self.names.remove(&peer_id);
+        record.paired_at = Some(unix_millis());
+        self.records.push(record);
+        self.sync_trusted();
+        true
+    }
+
+    pub fn remove(&mut self, peer_id: &PeerId) -> Option<PeerRecord> {
+        let index = self
+            .records
+            .iter()
+            .position(|record| record.peer_id == *peer_id)?;
+        let record = self.records.remove(index);
+        self.sync_trusted();
+        if let Some(name) = record.name.clone() {
+            self.set_untrusted_name(*peer_id, name);
+        }
+        Some(record)
+    }
+
+    // Store name advertised by peer, returns true if a trusted record changed
+    pub fn set_name(&mut self, peer_id: PeerId, name: String) -> bool {
+        let Some(name) = sanitize_name(&name) else {
+            return false;
+        };
+        match self.get_mut(&peer_id) {
+            Some(record) if record.name.as_ref() == Some(&name) => false,
+            Some(record) => {
+                record.name = Some(name);
+                true
+            }
+            None => {
+                self.set_untrusted_name(peer_id, name);
+                false
+            }
+        }
+    }
+
+    // Any device can advertise a name, so the map is capped
+    fn set_untrusted_name(&mut self, peer_id: PeerId, name: String) {
+        if self.names.len() < MAX_NAMES || self.names.contains_key(&peer_id) {
+            self.names.insert(peer_id, name);
+        }
+    }
+
+    // Peer was connected, address is kept if it can be dialed later
+    pub fn seen(&mut self, peer_id: &PeerId, addr: Option<&Multiaddr>) {
+        let Some(record) = self.get_mut(peer_id) else {
+            return;
+        };
+        record.last_seen = Some(unix_millis());
+        if let Some(addr) = addr {
+            self.add_addr(peer_id, addr);
+        }
+    }
+
+    // Address peer can be dialed on, returns true if it was not known yet
+    pub fn add_addr(&mut self, peer_id: &PeerId, addr: &Multiaddr) -> bool {
+        let Some(record) = self.get_mut(peer_id) else {
+            return false;
+        };
+        // Peers reported on several addresses would otherwise keep
+        // swapping them and data.json would be written every time
+        let known = record.addrs.contains(addr);
+        record.addrs.retain(|known| known != addr);
+        record.addrs.insert(0, addr.clone());
+        record.addrs.truncate(MAX_PEER_ADDRS);
+        !known
+    }
+
+    // Trusted peers that are not connected
+    pub fn offline<'a>(
+        &'a self,
+        connected: impl Fn(&PeerId) -> bool + 'a,
+    ) -> impl Iterator<Item = &'a PeerRecord> {
+        self.records
+            .iter()
+            .filter(move |record| !connected(&record.peer_id))
+    }
+
+    pub fn display_name(&self, peer_id: &PeerId) -> Option<String> {
+        match self.get(peer_id) {
+            Some(record) => record.display_name().map(str::to_string),
+            None => self.names.get(peer_id).cloned(),
+        }
+    }
+
+    // Find peer by peer id, alias or advertised name typed by user
+    // Names are only matched against trusted peers, others can pick any name
+    pub fn resolve(&self, query: &str) -> Result<PeerId, Box<dyn Error>> {
+        if let Ok(peer_id) = PeerId::from_str(query) {
+            return Ok(peer_id);
+        }
+        let mut found: Vec<PeerId> = self
+            .records
+            .iter()
+            .filter(|record| record.alias.as_deref() == Some(query))
+            .map(|record| record.peer_id)
+            .collect();
+        if found.is_empty() {
+            found = self
+                .records
+                .iter()
+                .filter(|record| record.name.as_deref() == Some(query))
+                .map(|record| record.peer_id)
+                .collect();
+        }
+        match found.as_slice() {
+            [peer_id] => Ok(*peer_id),
+            [] => Err(format!("Unknown peer {query}").into()),
+            _ => Err(format!(
+                "Several peers are named {query}, use alias or peer id"
+            )
+            .into()),
+        }
+    }
+
+    // Move values of old peer_policies and peer_names maps into records
+    fn migrate(&mut self, policies: Option<Value>, names: Option<Value>) {
+        log::info!("Migrating peers to peer records");
+        let policies: HashMap<String, PeerPolicy> = policies
+            .and_then(|policies| serde_json::from_value(policies).ok())
+            .unwrap_or_default();
+        let names: HashMap<String, Value> = names
+            .and_then(|names| serde_json::from_value(names).ok())
+            .unwrap_or_default();
+        for record in self.records.iter_mut() {
+            let peer_id = record.peer_id.to_string();
+            if let Some(policy) = policies.get(&peer_id) {
+                record.policy = *policy;
+            }
+            if let Some(name) = names.get(&peer_id) {
+                let field = |key: &str| name[key].as_str().map(str::to_string);
+                record.name = record.name.take().or(field("name"));
+                record.alias = record.alias.take().or(field("alias"));
+            }
+        }
+    }
+}
+
+// Older versions stored bare peer id strings
+fn parse_record(peer: Value) -> Result<PeerRecord, Box<dyn Error>> {
+    match peer {
+        Value::String(peer_id) => {
+            Ok(PeerRecord::new(PeerId::from_str(&peer_id)?))
+        }
+        peer => Ok(serde_json::from_value(peer)?),
+    }
+}
+
+// Names are shown in terminals, so control characters are dropped
+fn sanitize_name(name: &str) -> Option<String> {
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_NAME_LENGTH)
+        .collect();
+    let name = name.trim();
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+// Name of this device, hostname unless set in config
+pub fn device_name(configured: Option<&String>) -> String {
+    match configured {
+        Some(name) => name.clone(),
+        None => gethostname::gethostname().to_string_lossy().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::fs;
+
+    // Loads store from data.json with given content, returns it with saved data
+    fn load(data: Value) -> (PeerStore, Value) {
+        let dir = std::env::temp_dir()
+            .join(format!("resk-peers-{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("data.json");
+        fs::write(&path, data.to_string()).unwrap();
+        let store = PeerStore::load(&dir).unwrap();
+        let saved =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        (store, saved)
+    }
+
+    #[test]
+    fn loads_legacy_peer_id_strings() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let (store, _) =
+            load(json!({ "peers": [a.to_string(), b.to_string()] }));
+        let peers: Vec<PeerId> = store.trusted().copied().collect();
+        assert_eq!(peers, vec![a, b]);
+        let record = store.get(&a).unwrap();
+        assert_eq!(record.policy, PeerPolicy::Both);
+        assert_eq!(record.name, None);
+        assert!(record.addrs.is_empty());
+    }
+
+    #[test]
+    fn loads_mixed_strings_and_records() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let (store, _) = load(json!({
+            "peers": [
+                a.to_string(),
+                { "peer_id": b.to_string(), "alias": "laptop", "policy": "send" },
+            ]
+        }));
+        assert!(store.is_trusted(&a));
+        let record = store.get(&b).unwrap();
+        assert_eq!(record.alias.as_deref(), Some("laptop"));
+        assert_eq!(record.policy, PeerPolicy::Send);
+    }
+
+    #[test]
+    fn skips_invalid_and_duplicate_peers() {
+        let a = PeerId::random();
+        let (store, _) = load(json!({
+            "peers": [
+                "not a peer id",
+                { "peer_id": "not a peer id either" },
+                42,
+                a.to_string(),
+                { "peer_id": a.to_string(), "alias": "duplicate" },
+            ]
+        }));
+        assert_eq!(store.records().len(), 1);
+        assert_eq!(store.get(&a).unwrap().alias, None);
+
+        let (store, _) = load(json!({ "peers": "oops" }));
+        assert!(store.records().is_empty());
+    }
+
+    #[test]
+    fn migrates_policy_and_name_maps() {
+        let a = PeerId::random();
+        let (store, saved) = load(json!({
+            "port": "4000",
+            "peers": [a.to_string()],
+            "peer_policies": { a.to_string(): "receive" },
+            "peer_names": { a.to_string(): { "name": "desk", "alias": "work" } },
+        }));
+        let record = store.get(&a).unwrap();
+        assert_eq!(record.policy, PeerPolicy::Receive);
+        assert_eq!(record.name.as_deref(), Some("desk"));
+        assert_eq!(record.alias.as_deref(), Some("work"));
+
+        // Old maps are dropped and records are saved, other data is kept
+        assert!(saved.get("peer_policies").is_none());
+        assert!(saved.get("peer_names").is_none());
+        assert_eq!(saved["port"], "4000");
+        assert_eq!(saved["peers"][0]["peer_id"], a.to_string());
+        assert_eq!(saved["peers"][0]["policy"], "receive");
+    }
+
+    #[test]
+    fn add_addr_reports_only_new_addresses() {
+        let a = PeerId::random();
+        let (mut store, _) = load(json!({ "peers": [a.to_string()] }));
+        let tcp: Multiaddr = "/ip4/192.168.1.5/tcp/4001".parse().unwrap();
+        let quic: Multiaddr =
+            "/ip4/192.168.1.5/udp/4001/quic-v1".parse().unwrap();
+        assert!(store.add_addr(&a, &tcp));
+        assert!(store.add_addr(&a, &quic));
+        for _ in 0..3 {
+            assert!(!store.add_addr(&a, &tcp));
+            assert!(!store.add_addr(&a, &quic));
+        }
+        assert_eq!(store.get(&a).unwrap().addrs, vec![quic, tcp]);
+        assert!(!store.add_addr(
+            &PeerId::random(),
+            &"/ip4/1.2.3.4/tcp/1".parse().unwrap()
+        ));
+    }
+
+    #[test]
+    fn trusted_peers_follow_records() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let (mut store, _) = load(json!({ "peers": [a.to_string()] }));
+        let trusted = store.trusted_peers();
+        assert!(trusted.contains(&a));
+        assert!(!trusted.contains(&b));
+        store.add(b);
+        assert!(trusted.contains(&b));
+        store.remove(&a);
+        assert!(!trusted.contains(&a));
+    }
+
+    #[test]
+    fn parses_record_input() {
+        let a = PeerId::random();
+        let record = parse_record(json!({
+            "peer_id": a.to_string(),
+            "name": "phone",
+            "last_seen": 1700000000000u64,
+            "addrs": ["/ip4/192.168.1.5/tcp/4001"],
+            "policy": "none",
+        }))
+        .unwrap();
+        assert_eq!(record.peer_id, a);
+        assert_eq!(record.name.as_deref(), Some("phone"));
+        assert_eq!(record.last_seen, Some(1700000000000));
+        assert_eq!(record.addrs.len(), 1);
+        assert_eq!(record.policy, PeerPolicy::None);
+        assert!(parse_record(json!({ "name": "no peer id" })).is_err());
+    }
+}
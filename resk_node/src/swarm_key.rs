@@ -0,0 +1,46 @@
+// Pre-shared key of a private network
+// Connections are encrypted with it before any other handshake, so devices
+// without the same key can not connect at all
+use libp2p::pnet::PreSharedKey;
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::str::FromStr;
+
+// Same file name and format as go-libp2p and ipfs use
+pub const SWARM_KEY_FILE: &str = "swarm.key";
+
+// No key file means node is on the public network
+pub fn load_swarm_key(
+    data_dir: &Path,
+) -> Result<Option<PreSharedKey>, Box<dyn Error>> {
+    let path = data_dir.join(SWARM_KEY_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let key = fs::read_to_string(&path)?;
+    let key = PreSharedKey::from_str(&key)
+        .map_err(|err| format!("Invalid {}: {err}", path.display()))?;
+    Ok(Some(key))
+}
+
+// New key is never written over existing one, devices using it would be cut off
+pub fn generate_swarm_key(
+    data_dir: &Path,
+) -> Result<PreSharedKey, Box<dyn Error>> {
+    let path = data_dir.join(SWARM_KEY_FILE);
+    if path.exists() {
+        return Err(format!("{} already exists", path.display()).into());
+    }
+    let key = PreSharedKey::new(rand::random());
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(&path)?;
+    file.write_all(key.to_string().as_bytes())?;
+    Ok(key)
+}
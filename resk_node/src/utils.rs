@@ -5,32 +5,20 @@ use std::{
     fs::{self, OpenOptions},
     io::BufWriter,
     path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
 };
 #[cfg(any(
     target_os = "linux",
     target_os = "windows",
     target_os = "macos"
 ))]
-use {
-    lazy_static::lazy_static,
-    std::{env, str::FromStr},
-};
+use {resk_proto::transport::app_dir, std::env};
 
 use tokio::net::UdpSocket;
 
+use crate::clipboard_sync::SyncPause;
 use crate::{desktop, mobile};
 
-#[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
-lazy_static! {
-    // Reference to application's directory
-    pub static ref APP_DIR: PathBuf = {
-        let home = env::var("HOME").expect("HOME environment variable not found");
-        Path::new(&home).join(".resk")
-    };
-    // Reference to common data
-    pub static ref DATA_MAP: PathBuf = APP_DIR.clone().join("data.json");
-}
-
 pub async fn get_keys(
     data_dir: Option<String>,
     flutter_udp_port: Option<i32>,
@@ -42,7 +30,7 @@ pub async fn get_keys(
     // Initialization
     desktop!({
         shared_dir_path = env::var("HOME")?;
-        data_dir_path = APP_DIR.clone();
+        data_dir_path = app_dir()?;
     });
     mobile!({
         shared_dir_path = send_udp_msg_flutter(
@@ -95,11 +83,12 @@ pub async fn get_keys(
 
 #[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
 pub async fn save_port(port: u16) -> Result<(), Box<dyn Error>> {
-    let mut data_map = load_data_map(&*DATA_MAP)?;
+    let data_map_path = app_dir()?.join("data.json");
+    let mut data_map = load_data_map(&data_map_path)?;
 
     data_map.insert("port".to_string(), Value::String(port.to_string()));
 
-    write_json(&*DATA_MAP, &data_map)?;
+    write_json(&data_map_path, &data_map)?;
     Ok(())
 }
 #[cfg(any(target_os = "android", target_os = "ios"))]
@@ -130,32 +119,28 @@ pub fn load_data_map(
     Ok(data)
 }
 
-#[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
-pub fn save_peer(peer_id: &PeerId) -> Result<(), Box<dyn Error>> {
-    // Define vars
-    let mut data_map = load_data_map(&DATA_MAP)?;
-    let mut peers_list: Vec<Value>;
-
-    // Check if peers already saved
-    if data_map.contains_key("peers") {
-        peers_list = data_map.get("peers").unwrap().as_array().unwrap().clone();
-    } else {
-        peers_list = Vec::<Value>::new();
+pub fn load_sync_pause(data_dir: &Path) -> Result<SyncPause, Box<dyn Error>> {
+    let data_map = load_data_map(&data_dir.join("data.json"))?;
+    match data_map.get("sync") {
+        Some(sync) => Ok(serde_json::from_value(sync.clone())?),
+        None => Ok(SyncPause::default()),
     }
-    // Edit array
-    peers_list.push(Value::String(peer_id.to_string()));
-    data_map.insert("peers".to_string(), Value::Array(peers_list));
-
-    // Write back
-    write_json(&*DATA_MAP, &data_map)?;
-    Ok(())
 }
-#[cfg(any(target_os = "android", target_os = "ios"))]
-pub fn save_peer(_peer_id: &PeerId) -> Result<(), Box<dyn Error>> {
+
+pub fn save_sync_pause(
+    data_dir: &Path,
+    sync_pause: &SyncPause,
+) -> Result<(), Box<dyn Error>> {
+    let data_map_path = data_dir.join("data.json");
+    let mut data_map = load_data_map(&data_map_path)?;
+
+    data_map.insert("sync".to_string(), serde_json::to_value(sync_pause)?);
+
+    write_json(&data_map_path, &data_map)?;
     Ok(())
 }
 
-fn write_json(
+pub fn write_json(
     path: &PathBuf,
     data: &Map<String, Value>,
 ) -> Result<(), Box<dyn Error>> {
@@ -169,25 +154,6 @@ fn write_json(
     Ok(())
 }
 
-#[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
-pub fn load_known_peers() -> Result<Vec<PeerId>, Box<dyn Error>> {
-    let mut known_peers = vec![];
-    let data_map = load_data_map(&DATA_MAP)?;
-    if data_map.contains_key("peers") {
-        let peers_list =
-            data_map.get("peers").unwrap().as_array().unwrap().clone();
-        for peer_id in peers_list.into_iter() {
-            let peer_id = PeerId::from_str(&peer_id.as_str().unwrap())?;
-            known_peers.push(peer_id);
-        }
-    }
-    Ok(known_peers)
-}
-#[cfg(any(target_os = "android", target_os = "ios"))]
-pub fn load_known_peers() -> Result<Vec<PeerId>, Box<dyn Error>> {
-    Ok(vec![])
-}
-
 #[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
 pub async fn init_backend_listener() -> Result<UdpSocket, Box<dyn Error>> {
     let backend_listener = UdpSocket::bind("127.0.0.1:0").await?;
@@ -220,3 +186,11 @@ pub async fn send_udp_msg_flutter(
     let buf = String::from_utf8_lossy(&buf);
     Ok(buf.to_string())
 }
+
+// Current unix time in milliseconds
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
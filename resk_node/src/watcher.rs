@@ -0,0 +1,113 @@
+// Clipboard change watching
+// Event driven backends are preferred, pooling is only used as a fallback
+#[cfg(any(
+    target_os = "linux",
+    target_os = "windows",
+    target_os = "macos"
+))]
+use crate::desktop::DesktopClipboard;
+#[cfg(any(target_os = "android", target_os = "ios"))]
+use crate::mobile::MobileClipboard;
+
+use std::collections::HashMap;
+use tokio::sync::mpsc::Sender;
+use tokio::time::{sleep, Duration};
+
+use crate::clipboard_sync::ClipboardContent;
+use crate::{desktop, mobile};
+
+#[cfg(target_os = "linux")]
+mod wayland;
+#[cfg(target_os = "linux")]
+mod x11;
+
+// Value copied on this device
+#[derive(Debug, Clone)]
+pub struct LocalCopy {
+    pub content: ClipboardContent,
+    // Password manager hints offered together with content
+    // MIME type mapped to its value
+    pub hints: HashMap<String, String>,
+}
+
+impl LocalCopy {
+    pub fn new(content: ClipboardContent) -> Self {
+        LocalCopy {
+            content,
+            hints: HashMap::new(),
+        }
+    }
+}
+
+// Watch clipboard and send every new value to sender
+// Falls back to pooling when no event source is available
+pub async fn start_watching_clipboard(
+    sender: Sender<LocalCopy>,
+    flutter_udp_port: Option<i32>,
+) {
+    #[cfg(target_os = "linux")]
+    {
+        match wayland::spawn_watcher(sender.clone()) {
+            Ok(()) => {
+                log::info!("Watching clipboard via Wayland data-control");
+                return;
+            }
+            Err(err) => {
+                log::info!("Wayland clipboard watcher unavailable: {err}")
+            }
+        }
+        match x11::spawn_watcher(sender.clone()) {
+            Ok(()) => {
+                log::info!("Watching clipboard via X11 XFixes");
+                return;
+            }
+            Err(err) => log::info!("X11 clipboard watcher unavailable: {err}"),
+        }
+    }
+    log::info!("Falling back to clipboard pooling");
+    start_pooling_clipboard(sender, flutter_udp_port).await;
+}
+
+async fn start_pooling_clipboard(
+    sender: Sender<LocalCopy>,
+    flutter_udp_port: Option<i32>,
+) {
+    // Init clipboard, only mobile one talks to flutter app
+    desktop! {
+        let _ = flutter_udp_port;
+        let mut clipboard: DesktopClipboard =
+            DesktopClipboard::new().expect("Failed to init clipboard")
+    };
+    mobile! {
+        let mut clipboard: MobileClipboard =
+            MobileClipboard::new(flutter_udp_port.clone().unwrap())
+                .expect("Failed to init clipboard")
+
+    };
+
+    // Get initial clipboard content
+    let mut last_clipboard_content = clipboard
+        .get_content()
+        .expect("Failed to get clipboard content");
+
+    // Check if it was changed
+    loop {
+        sleep(Duration::from_secs(1)).await;
+        let current_clipboard_content = match clipboard.get_content() {
+            Ok(content) => content,
+            Err(err) => {
+                log::debug!("Failed to get clipboard content: {err}");
+                continue;
+            }
+        };
+        if current_clipboard_content != last_clipboard_content {
+            last_clipboard_content = current_clipboard_content.clone();
+            if let Some(content) = current_clipboard_content {
+                sender
+                    .send(LocalCopy::new(content))
+                    .await
+                    .expect("Failed to send value");
+            }
+        }
+    }
+}
@@ -0,0 +1,338 @@
+// Wayland backend: reacts to selection offers of the wlr data-control protocol
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{self, Read};
+use std::os::fd::{AsFd, AsRawFd, RawFd};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::Sender;
+use wayland_client::backend::ObjectId;
+use wayland_client::protocol::{wl_registry, wl_seat::WlSeat};
+use wayland_client::{
+    event_created_child, Connection, Dispatch, Proxy, QueueHandle,
+};
+use wayland_protocols_wlr::data_control::v1::client::{
+    zwlr_data_control_device_v1::{self, ZwlrDataControlDeviceV1},
+    zwlr_data_control_manager_v1::ZwlrDataControlManagerV1,
+    zwlr_data_control_offer_v1::{self, ZwlrDataControlOfferV1},
+};
+
+use crate::clipboard_sync::ClipboardContent;
+use crate::filter::is_hint;
+use crate::payload::{MIME_HTML, MIME_PNG};
+use crate::watcher::LocalCopy;
+
+// Text MIME types in order of preference
+const TEXT_MIME_TYPES: [&str; 5] = [
+    "text/plain;charset=utf-8",
+    "UTF8_STRING",
+    "text/plain",
+    "TEXT",
+    "STRING",
+];
+
+// Source that never closes its end of the pipe must not stall the watcher
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct WatcherState {
+    seat: Option<WlSeat>,
+    manager: Option<ZwlrDataControlManagerV1>,
+    // MIME types announced by every live offer
+    offers: HashMap<ObjectId, Vec<String>>,
+    // Latest clipboard offer that was not read yet
+    selection: Option<ZwlrDataControlOfferV1>,
+    finished: bool,
+}
+
+pub fn spawn_watcher(sender: Sender<LocalCopy>) -> Result<(), Box<dyn Error>> {
+    let conn = Connection::connect_to_env()?;
+    let mut queue = conn.new_event_queue();
+    let qh = queue.handle();
+    conn.display().get_registry(&qh, ());
+
+    let mut state = WatcherState::default();
+    queue.roundtrip(&mut state)?;
+    let (seat, manager) = match (&state.seat, &state.manager) {
+        (Some(seat), Some(manager)) => (seat.clone(), manager.clone()),
+        _ => return Err("compositor does not support data-control".into()),
+    };
+    manager.get_data_device(&seat, &qh, ());
+
+    // Skip selection that was present before watching started
+    queue.roundtrip(&mut state)?;
+    if let Some(offer) = state.selection.take() {
+        state.drop_offer(offer);
+    }
+
+    thread::spawn(move || loop {
+        if let Err(err) = queue.blocking_dispatch(&mut state) {
+            log::error!("Wayland clipboard watcher stopped: {err}");
+            break;
+        }
+        if state.finished {
+            log::error!("Wayland clipboard device was destroyed");
+            break;
+        }
+        let Some(offer) = state.selection.take() else {
+            continue;
+        };
+        let content = state.read_content(&conn, &offer);
+        let hints = state.read_hints(&conn, &offer);
+        state.drop_offer(offer);
+        match content {
+            Ok(Some(content)) => {
+                let copy = LocalCopy { content, hints };
+                if sender.blocking_send(copy).is_err() {
+                    break;
+                }
+            }
+            Ok(None) => log::debug!("Clipboard offer has no supported content"),
+            Err(err) => log::debug!("Failed to read clipboard: {err}"),
+        }
+    });
+    Ok(())
+}
+
+impl WatcherState {
+    // Read offer as text if possible, together with html if it is offered
+    // Otherwise read it as image
+    fn read_content(
+        &self,
+        conn: &Connection,
+        offer: &ZwlrDataControlOfferV1,
+    ) -> Result<Option<ClipboardContent>, Box<dyn Error>> {
+        let Some(mime_types) = self.offers.get(&offer.id()) else {
+            return Ok(None);
+        };
+        let is_offered =
+            |mime_type: &str| mime_types.iter().any(|m| m == mime_type);
+        if let Some(mime_type) = TEXT_MIME_TYPES
+            .iter()
+            .find(|mime_type| is_offered(mime_type))
+        {
+            let text = read_offer(conn, offer, mime_type)?;
+            let text = String::from_utf8_lossy(&text).to_string();
+            if !is_offered(MIME_HTML) {
+                return Ok(Some(ClipboardContent::Text(text)));
+            }
+            let html = read_offer(conn, offer, MIME_HTML)?;
+            return Ok(Some(ClipboardContent::Html {
+                html: String::from_utf8_lossy(&html).to_string(),
+                text,
+            }));
+        }
+        if is_offered(MIME_PNG) {
+            let png = read_offer(conn, offer, MIME_PNG)?;
+            return Ok(Some(ClipboardContent::Image(png)));
+        }
+        Ok(None)
+    }
+
+    // Values of password manager hints offered together with content
+    fn read_hints(
+        &self,
+        conn: &Connection,
+        offer: &ZwlrDataControlOfferV1,
+    ) -> HashMap<String, String> {
+        let mut hints = HashMap::new();
+        let Some(mime_types) = self.offers.get(&offer.id()) else {
+            return hints;
+        };
+        for mime_type in mime_types.iter().filter(|m| is_hint(m)) {
+            match read_offer(conn, offer, mime_type) {
+                Ok(value) => {
+                    let value = String::from_utf8_lossy(&value).to_string();
+                    hints.insert(mime_type.clone(), value);
+                }
+                Err(err) => log::debug!("Failed to read {mime_type}: {err}"),
+            }
+        }
+        hints
+    }
+
+    fn drop_offer(&mut self, offer: ZwlrDataControlOfferV1) {
+        self.offers.remove(&offer.id());
+        offer.destroy();
+    }
+}
+
+fn read_offer(
+    conn: &Connection,
+    offer: &ZwlrDataControlOfferV1,
+    mime_type: &str,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let (mut reader, writer) = os_pipe::pipe()?;
+    offer.receive(mime_type.to_string(), writer.as_fd());
+    conn.flush()?;
+    // Our end of the writer has to be closed to get EOF
+    drop(writer);
+
+    set_nonblocking(reader.as_raw_fd())?;
+    let deadline = Instant::now() + READ_TIMEOUT;
+    let mut content = Vec::new();
+    let mut buf = [0; 64 * 1024];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => return Ok(content),
+            Ok(len) => content.extend_from_slice(&buf[..len]),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                let remaining =
+                    deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero()
+                    || !wait_readable(reader.as_raw_fd(), remaining)?
+                {
+                    return Err(format!(
+                        "Source did not send {mime_type} in time"
+                    )
+                    .into());
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    // SAFETY: fd is owned by the pipe reader and stays open during the call
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0
+        || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) }
+            < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// False if nothing arrived before timeout
+fn wait_readable(fd: RawFd, timeout: Duration) -> io::Result<bool> {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let timeout = timeout.as_millis().clamp(1, i32::MAX as u128) as i32;
+    // SAFETY: pollfd is a single valid entry for the duration of the call
+    match unsafe { libc::poll(&mut pollfd, 1, timeout) } {
+        0 => Ok(false),
+        n if n > 0 => Ok(true),
+        _ => {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                return Ok(true);
+            }
+            Err(err)
+        }
+    }
+}
+
+impl Dispatch<wl_registry::WlRegistry, ()> for WatcherState {
+    fn event(
+        state: &mut Self,
+        registry: &wl_registry::WlRegistry,
+        event: wl_registry::Event,
+        _: &(),
+        _: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        if let wl_registry::Event::Global {
+            name,
+            interface,
+            version,
+        } = event
+        {
+            match interface.as_str() {
+                "wl_seat" if state.seat.is_none() => {
+                    state.seat =
+                        Some(registry.bind(name, version.min(7), qh, ()));
+                }
+                "zwlr_data_control_manager_v1" => {
+                    state.manager =
+                        Some(registry.bind(name, version.min(2), qh, ()));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Dispatch<WlSeat, ()> for WatcherState {
+    fn event(
+        _: &mut Self,
+        _: &WlSeat,
+        _: <WlSeat as Proxy>::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ZwlrDataControlManagerV1, ()> for WatcherState {
+    fn event(
+        _: &mut Self,
+        _: &ZwlrDataControlManagerV1,
+        _: <ZwlrDataControlManagerV1 as Proxy>::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ZwlrDataControlDeviceV1, ()> for WatcherState {
+    fn event(
+        state: &mut Self,
+        _: &ZwlrDataControlDeviceV1,
+        event: zwlr_data_control_device_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        match event {
+            zwlr_data_control_device_v1::Event::DataOffer { id } => {
+                state.offers.insert(id.id(), vec![]);
+            }
+            zwlr_data_control_device_v1::Event::Selection { id } => {
+                // Replaced offer will never be read
+                if let Some(offer) = state.selection.take() {
+                    state.drop_offer(offer);
+                }
+                state.selection = id;
+            }
+            zwlr_data_control_device_v1::Event::PrimarySelection {
+                id: Some(offer),
+            } => {
+                // Only CLIPBOARD is shared
+                state.drop_offer(offer);
+            }
+            zwlr_data_control_device_v1::Event::Finished => {
+                state.finished = true;
+            }
+            _ => {}
+        }
+    }
+
+    event_created_child!(WatcherState, ZwlrDataControlDeviceV1, [
+        zwlr_data_control_device_v1::EVT_DATA_OFFER_OPCODE => (ZwlrDataControlOfferV1, ()),
+    ]);
+}
+
+impl Dispatch<ZwlrDataControlOfferV1, ()> for WatcherState {
+    fn event(
+        state: &mut Self,
+        offer: &ZwlrDataControlOfferV1,
+        event: zwlr_data_control_offer_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let zwlr_data_control_offer_v1::Event::Offer { mime_type } = event {
+            if let Some(mime_types) = state.offers.get_mut(&offer.id()) {
+                mime_types.push(mime_type);
+            }
+        }
+    }
+}
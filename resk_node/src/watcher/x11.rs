@@ -0,0 +1,197 @@
+// X11 backend: reacts to CLIPBOARD owner changes reported by XFixes
+use std::collections::HashMap;
+use std::error::Error;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::Sender;
+use x11rb::connection::Connection;
+use x11rb::protocol::xfixes::{ConnectionExt as _, SelectionEventMask};
+use x11rb::protocol::xproto::{
+    Atom, AtomEnum, ConnectionExt as _, CreateWindowAux, GetPropertyReply,
+    Window, WindowClass,
+};
+use x11rb::protocol::Event;
+use x11rb::rust_connection::RustConnection;
+use x11rb::{COPY_DEPTH_FROM_PARENT, CURRENT_TIME, NONE};
+
+use crate::desktop::DesktopClipboard;
+use crate::filter::is_hint;
+use crate::watcher::LocalCopy;
+
+// Time given to clipboard owner to answer a conversion request
+const CONVERT_TIMEOUT: Duration = Duration::from_millis(500);
+
+pub fn spawn_watcher(sender: Sender<LocalCopy>) -> Result<(), Box<dyn Error>> {
+    let (conn, screen_num) = x11rb::connect(None)?;
+
+    // XFixes has to be negotiated before its requests can be used
+    conn.xfixes_query_version(5, 0)?.reply()?;
+
+    // Hidden window that receives selection events
+    let window = create_window(&conn, screen_num)?;
+    let clipboard_atom = intern_atom(&conn, "CLIPBOARD")?;
+    conn.xfixes_select_selection_input(
+        window,
+        clipboard_atom,
+        SelectionEventMask::SET_SELECTION_OWNER,
+    )?;
+    conn.flush()?;
+
+    // Separate connection to read hints, so no owner change events are lost
+    let hint_reader = HintReader::new()?;
+
+    thread::spawn(move || {
+        let mut clipboard =
+            DesktopClipboard::new().expect("Failed to init clipboard");
+        loop {
+            match conn.wait_for_event() {
+                Ok(Event::XfixesSelectionNotify(_)) => {
+                    let content = match clipboard.get_content() {
+                        Ok(Some(content)) => content,
+                        Ok(None) => continue,
+                        Err(err) => {
+                            log::debug!("Failed to read clipboard: {err}");
+                            continue;
+                        }
+                    };
+                    let hints = hint_reader.read().unwrap_or_else(|err| {
+                        log::debug!("Failed to read clipboard hints: {err}");
+                        HashMap::new()
+                    });
+                    let copy = LocalCopy { content, hints };
+                    if sender.blocking_send(copy).is_err() {
+                        break;
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    log::error!("X11 clipboard watcher stopped: {err}");
+                    break;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+fn create_window(
+    conn: &RustConnection,
+    screen_num: usize,
+) -> Result<Window, Box<dyn Error>> {
+    let screen = &conn.setup().roots[screen_num];
+    let window = conn.generate_id()?;
+    conn.create_window(
+        COPY_DEPTH_FROM_PARENT,
+        window,
+        screen.root,
+        0,
+        0,
+        1,
+        1,
+        0,
+        WindowClass::INPUT_OUTPUT,
+        screen.root_visual,
+        &CreateWindowAux::new(),
+    )?;
+    Ok(window)
+}
+
+fn intern_atom(
+    conn: &RustConnection,
+    name: &str,
+) -> Result<Atom, Box<dyn Error>> {
+    Ok(conn.intern_atom(false, name.as_bytes())?.reply()?.atom)
+}
+
+// Reads password manager hints offered by CLIPBOARD owner
+struct HintReader {
+    conn: RustConnection,
+    window: Window,
+    clipboard: Atom,
+    targets: Atom,
+    // Property of our window where owner puts converted values
+    property: Atom,
+}
+
+impl HintReader {
+    fn new() -> Result<Self, Box<dyn Error>> {
+        let (conn, screen_num) = x11rb::connect(None)?;
+        let window = create_window(&conn, screen_num)?;
+        let clipboard = intern_atom(&conn, "CLIPBOARD")?;
+        let targets = intern_atom(&conn, "TARGETS")?;
+        let property = intern_atom(&conn, "RESK_SELECTION")?;
+        Ok(HintReader {
+            conn,
+            window,
+            clipboard,
+            targets,
+            property,
+        })
+    }
+
+    fn read(&self) -> Result<HashMap<String, String>, Box<dyn Error>> {
+        let mut hints = HashMap::new();
+        let Some(targets) = self.convert(self.targets)? else {
+            return Ok(hints);
+        };
+        let Some(targets) = targets.value32() else {
+            return Ok(hints);
+        };
+        for target in targets.collect::<Vec<Atom>>() {
+            let name = self.conn.get_atom_name(target)?.reply()?.name;
+            let name = String::from_utf8_lossy(&name).to_string();
+            if !is_hint(&name) {
+                continue;
+            }
+            if let Some(value) = self.convert(target)? {
+                let value = String::from_utf8_lossy(&value.value).to_string();
+                hints.insert(name, value);
+            }
+        }
+        Ok(hints)
+    }
+
+    // Ask CLIPBOARD owner for target and wait for its answer
+    fn convert(
+        &self,
+        target: Atom,
+    ) -> Result<Option<GetPropertyReply>, Box<dyn Error>> {
+        self.conn.convert_selection(
+            self.window,
+            self.clipboard,
+            target,
+            self.property,
+            CURRENT_TIME,
+        )?;
+        self.conn.flush()?;
+        let deadline = Instant::now() + CONVERT_TIMEOUT;
+        loop {
+            match self.conn.poll_for_event()? {
+                Some(Event::SelectionNotify(event))
+                    if event.target == target =>
+                {
+                    if event.property == NONE {
+                        return Ok(None);
+                    }
+                    let reply = self
+                        .conn
+                        .get_property(
+                            true,
+                            self.window,
+                            self.property,
+                            AtomEnum::ANY,
+                            0,
+                            u32::MAX,
+                        )?
+                        .reply()?;
+                    return Ok(Some(reply));
+                }
+                Some(_) => {}
+                None if Instant::now() > deadline => {
+                    return Err("Clipboard owner did not answer".into())
+                }
+                None => thread::sleep(Duration::from_millis(5)),
+            }
+        }
+    }
+}
@@ -0,0 +1,509 @@
+// Control protocol between resk_node and client apps
+// Every frame is a json object with protocol version and request id, node
+// answers with the same id and either a response or an error with a code
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+
+pub mod transport;
+
+// Bumped on incompatible changes, node rejects other versions
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RequestFrame {
+    pub version: u32,
+    pub id: u64,
+    #[serde(flatten)]
+    pub request: Request,
+}
+
+impl RequestFrame {
+    pub fn new(id: u64, request: Request) -> Self {
+        RequestFrame {
+            version: PROTOCOL_VERSION,
+            id,
+            request,
+        }
+    }
+}
+
+// Sent as either result or error field, like in json-rpc
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResponseFrame {
+    pub version: u32,
+    pub id: u64,
+    #[serde(flatten, with = "outcome")]
+    pub result: Result<Response, ProtoError>,
+}
+
+impl ResponseFrame {
+    pub fn new(id: u64, result: Result<Response, ProtoError>) -> Self {
+        ResponseFrame {
+            version: PROTOCOL_VERSION,
+            id,
+            result,
+        }
+    }
+}
+
+mod outcome {
+    use super::{ProtoError, Response};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    enum Outcome {
+        Result(Response),
+        Error(ProtoError),
+    }
+
+    pub fn serialize<S: Serializer>(
+        result: &Result<Response, ProtoError>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match result {
+            Ok(response) => Outcome::Result(response.clone()),
+            Err(err) => Outcome::Error(err.clone()),
+        }
+        .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Result<Response, ProtoError>, D::Error> {
+        Ok(match Outcome::deserialize(deserializer)? {
+            Outcome::Result(response) => Ok(response),
+            Outcome::Error(err) => Err(err),
+        })
+    }
+}
+
+// Peers can be given by peer id, alias or advertised name
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case", tag = "method", content = "params")]
+pub enum Request {
+    IsAlive,
+    // Peers found on local network or connected directly
+    GetPeers,
+    // Trusted peers
+    Peers,
+    AddPeer { peer: String },
+    RemovePeer { peer: String },
+    // No alias removes it
+    SetAlias { peer: String, alias: Option<String> },
+    SetPolicy { peer: String, policy: PeerPolicy },
+    Connect { addr: String },
+    PairUri,
+    PairRedeem { uri: String },
+    Pairings,
+    PairConfirm { peer: String },
+    PairReject { peer: String },
+    SwarmKey,
+    GenerateSwarmKey,
+    LocalPeerId,
+    History { query: Option<String> },
+    HistoryShow { id: u64 },
+    HistoryApply { id: u64 },
+    ClipSend { text: String },
+    ClipGet,
+    Pause { direction: Direction },
+    Resume { direction: Direction },
+    SyncStatus,
+    // Answered with Ok, then connection only carries events
+    Subscribe,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case", tag = "type", content = "data")]
+pub enum Response {
+    Ok,
+    OnlinePeers(Vec<OnlinePeer>),
+    Peers(Vec<PeerInfo>),
+    PeerId(String),
+    PairUri(String),
+    Pairings(Vec<PairingSummary>),
+    // Fingerprint of swarm key, None outside of private network
+    SwarmKey(Option<String>),
+    History(Vec<HistorySummary>),
+    Text(String),
+    SyncStatus(SyncStatus),
+    Event(Event),
+}
+
+// Sent to subscribed client apps as things happen on node
+// Names are the ones shown to users, None while peer has not told its name
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case", tag = "event")]
+pub enum Event {
+    // Found by mdns on local network
+    PeerDiscovered {
+        peer_id: String,
+        name: Option<String>,
+        addr: String,
+    },
+    PeerExpired {
+        peer_id: String,
+        name: Option<String>,
+        addr: String,
+    },
+    // First connection to peer, later ones are not reported
+    ConnectionEstablished {
+        peer_id: String,
+        name: Option<String>,
+        addr: String,
+    },
+    // Last connection to peer was closed
+    ConnectionClosed {
+        peer_id: String,
+        name: Option<String>,
+    },
+    // Update from peer was put on local clipboard
+    ClipboardReceived {
+        origin: String,
+        origin_name: Option<String>,
+        mime: String,
+        size: usize,
+    },
+    // Local copy was shared with peers
+    ClipboardSent {
+        mime: String,
+        size: usize,
+        peers: Vec<String>,
+    },
+    // Peer started pairing, user has to confirm the code
+    PairingRequested {
+        peer_id: String,
+        name: Option<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProtoError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ProtoError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ProtoError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ProtoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for ProtoError {}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    // Frame could not be parsed
+    InvalidRequest,
+    UnsupportedVersion,
+    InvalidArgument,
+    UnknownPeer,
+    NotFound,
+    // Request was valid, but node could not do it
+    Failed,
+    // Response does not fit in a udp datagram
+    TooLarge,
+    // Request is only served over control socket
+    NotAllowed,
+}
+
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    #[default]
+    Both,
+    Incoming,
+    Outgoing,
+}
+
+impl Direction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Both => "both",
+            Direction::Incoming => "incoming",
+            Direction::Outgoing => "outgoing",
+        }
+    }
+}
+
+// Per-peer routing of clipboard updates
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum PeerPolicy {
+    // Local copies are sent to peer, its updates are ignored
+    Send,
+    // Updates from peer are applied, local copies are not sent to it
+    Receive,
+    #[default]
+    Both,
+    None,
+}
+
+impl PeerPolicy {
+    pub fn can_send(&self) -> bool {
+        matches!(self, PeerPolicy::Send | PeerPolicy::Both)
+    }
+
+    pub fn can_receive(&self) -> bool {
+        matches!(self, PeerPolicy::Receive | PeerPolicy::Both)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PeerPolicy::Send => "send",
+            PeerPolicy::Receive => "receive",
+            PeerPolicy::Both => "both",
+            PeerPolicy::None => "none",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OnlinePeer {
+    pub peer_id: String,
+    pub addr: String,
+    pub name: Option<String>,
+}
+
+// Trusted peer as stored by node
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PeerInfo {
+    pub peer_id: String,
+    pub name: Option<String>,
+    pub alias: Option<String>,
+    // Unix time in milliseconds
+    pub paired_at: Option<u64>,
+    pub last_seen: Option<u64>,
+    pub addrs: Vec<String>,
+    pub policy: PeerPolicy,
+}
+
+impl PeerInfo {
+    // Alias wins over advertised name
+    pub fn display_name(&self) -> Option<&str> {
+        self.alias.as_deref().or(self.name.as_deref())
+    }
+}
+
+// Pairing as shown to client apps
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PairingSummary {
+    pub peer_id: String,
+    // Name of peer, filled in by run_node
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub initiator: bool,
+    pub code: Option<String>,
+    pub local_confirmed: bool,
+    pub remote_confirmed: bool,
+    #[serde(flatten)]
+    pub state: PairingState,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", tag = "state", content = "reason")]
+pub enum PairingState {
+    // Nonces are being exchanged
+    Waiting,
+    // Code is known and has to be confirmed by users
+    Confirming,
+    Paired,
+    Failed(String),
+}
+
+// Short description of history entry
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistorySummary {
+    pub id: u64,
+    pub origin: String,
+    // Name of origin device, filled in by run_node
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin_name: Option<String>,
+    // Unix time in milliseconds
+    pub timestamp: u64,
+    pub mime: String,
+    pub preview: String,
+}
+
+// Directions of sharing paused by user
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct SyncStatus {
+    pub incoming: bool,
+    pub outgoing: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn round_trip<T: Serialize + for<'de> Deserialize<'de>>(
+        value: &T,
+    ) -> (Value, T) {
+        let encoded = serde_json::to_vec(value).unwrap();
+        let json = serde_json::from_slice(&encoded).unwrap();
+        (json, serde_json::from_slice(&encoded).unwrap())
+    }
+
+    #[test]
+    fn request_frame_round_trip() {
+        let frame = RequestFrame::new(
+            7,
+            Request::SetAlias {
+                peer: "laptop".to_string(),
+                alias: Some("work".to_string()),
+            },
+        );
+        let (json, decoded) = round_trip(&frame);
+        assert_eq!(
+            json,
+            json!({
+                "version": 1,
+                "id": 7,
+                "method": "set_alias",
+                "params": { "peer": "laptop", "alias": "work" }
+            })
+        );
+        assert_eq!(decoded.version, PROTOCOL_VERSION);
+        assert_eq!(decoded.id, 7);
+        assert!(matches!(
+            decoded.request,
+            Request::SetAlias { peer, alias: Some(alias) }
+                if peer == "laptop" && alias == "work"
+        ));
+    }
+
+    #[test]
+    fn set_policy_round_trip() {
+        let (json, decoded) = round_trip(&RequestFrame::new(
+            2,
+            Request::SetPolicy {
+                peer: "laptop".to_string(),
+                policy: PeerPolicy::Receive,
+            },
+        ));
+        assert_eq!(
+            json["params"],
+            json!({ "peer": "laptop", "policy": "receive" })
+        );
+        assert!(matches!(
+            decoded.request,
+            Request::SetPolicy {
+                policy: PeerPolicy::Receive,
+                ..
+            }
+        ));
+        let unknown = json!({
+            "version": 1,
+            "id": 2,
+            "method": "set_policy",
+            "params": { "peer": "laptop", "policy": "all" }
+        });
+        assert!(serde_json::from_value::<RequestFrame>(unknown).is_err());
+    }
+
+    #[test]
+    fn request_without_params_round_trip() {
+        let (json, decoded) =
+            round_trip(&RequestFrame::new(1, Request::IsAlive));
+        assert_eq!(
+            json,
+            json!({ "version": 1, "id": 1, "method": "is_alive" })
+        );
+        assert!(matches!(decoded.request, Request::IsAlive));
+    }
+
+    #[test]
+    fn response_frame_result_round_trip() {
+        let frame = ResponseFrame::new(
+            3,
+            Ok(Response::SyncStatus(SyncStatus {
+                incoming: true,
+                outgoing: false,
+            })),
+        );
+        let (json, decoded) = round_trip(&frame);
+        assert_eq!(
+            json,
+            json!({
+                "version": 1,
+                "id": 3,
+                "result": {
+                    "type": "sync_status",
+                    "data": { "incoming": true, "outgoing": false }
+                }
+            })
+        );
+        assert_eq!(decoded.id, 3);
+        assert!(matches!(
+            decoded.result,
+            Ok(Response::SyncStatus(SyncStatus {
+                incoming: true,
+                outgoing: false
+            }))
+        ));
+    }
+
+    #[test]
+    fn response_frame_error_round_trip() {
+        let frame = ResponseFrame::new(
+            4,
+            Err(ProtoError::new(ErrorCode::UnknownPeer, "No peer named tv")),
+        );
+        let (json, decoded) = round_trip(&frame);
+        assert_eq!(
+            json,
+            json!({
+                "version": 1,
+                "id": 4,
+                "error": { "code": "unknown_peer", "message": "No peer named tv" }
+            })
+        );
+        let err = decoded.result.unwrap_err();
+        assert_eq!(err.code, ErrorCode::UnknownPeer);
+        assert_eq!(err.to_string(), "No peer named tv");
+    }
+
+    // Node has to read frames of other versions to answer them with an error
+    #[test]
+    fn frames_of_other_versions_are_decoded() {
+        let request: RequestFrame = serde_json::from_value(
+            json!({ "version": 2, "id": 9, "method": "peers" }),
+        )
+        .unwrap();
+        assert_eq!(request.version, 2);
+        assert_eq!(request.id, 9);
+        assert!(matches!(request.request, Request::Peers));
+
+        let mut response = ResponseFrame::new(
+            9,
+            Err(ProtoError::new(
+                ErrorCode::UnsupportedVersion,
+                "Unsupported protocol version 2",
+            )),
+        );
+        response.version = 2;
+        let (json, decoded) = round_trip(&response);
+        assert_eq!(json["version"], 2);
+        assert_eq!(decoded.version, 2);
+        assert_eq!(
+            decoded.result.unwrap_err().code,
+            ErrorCode::UnsupportedVersion
+        );
+    }
+}
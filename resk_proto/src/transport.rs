@@ -0,0 +1,69 @@
+// Where client apps find node and how frames are sent to it
+// Streams carry frames prefixed with their length, datagrams carry a
+// single frame each
+use std::env;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// Biggest payload of a single udp datagram
+pub const MAX_DATAGRAM_SIZE: usize = 65507;
+// Bigger frames are treated as broken stream
+const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+// Data directory of desktop node
+pub fn app_dir() -> io::Result<PathBuf> {
+    let home = env::var_os("HOME").ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            "HOME environment variable not found",
+        )
+    })?;
+    Ok(Path::new(&home).join(".resk"))
+}
+
+// $XDG_RUNTIME_DIR/resk/control.sock, app dir if runtime dir is not set
+pub fn socket_path() -> io::Result<PathBuf> {
+    let dir = match env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime_dir) => PathBuf::from(runtime_dir).join("resk"),
+        None => app_dir()?,
+    };
+    Ok(dir.join("control.sock"))
+}
+
+// None when peer closed connection between frames
+pub async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> io::Result<Option<Vec<u8>>> {
+    let len = match reader.read_u32().await {
+        Ok(len) => len as usize,
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+            return Ok(None)
+        }
+        Err(err) => return Err(err),
+    };
+    if len > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Frame of {len} bytes is too big"),
+        ));
+    }
+    let mut frame = vec![0; len];
+    reader.read_exact(&mut frame).await?;
+    Ok(Some(frame))
+}
+
+pub async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    frame: &[u8],
+) -> io::Result<()> {
+    if frame.len() > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Frame of {} bytes is too big", frame.len()),
+        ));
+    }
+    writer.write_u32(frame.len() as u32).await?;
+    writer.write_all(frame).await?;
+    writer.flush().await
+}